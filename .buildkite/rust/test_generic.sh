@@ -38,4 +38,8 @@ pushd $src_dir
 
   CARGO_TARGET_DIR="${CARGO_TARGET_DIR}/default" cargo test --workspace --locked \
     --exclude simple-keyvalue
+
+  # Run the runtime tests again with MKVS invariant checks enabled.
+  CARGO_TARGET_DIR="${CARGO_TARGET_DIR}/default" cargo test --locked \
+    --package oasis-core-runtime --features debug-invariants
 popd
@@ -127,6 +127,69 @@ impl KeyFormatAtom for u8 {
     }
 }
 
+/// Implement `KeyFormatAtom` for unsigned integer types using big-endian encoding, so that
+/// the byte order of encoded atoms matches the numeric order.
+macro_rules! impl_unsigned_atom {
+    ($($ty:ty),*) => {
+        $(
+            impl KeyFormatAtom for $ty {
+                fn size() -> usize {
+                    size_of::<$ty>()
+                }
+
+                fn encode_atom(self) -> Vec<u8> {
+                    self.to_be_bytes().to_vec()
+                }
+
+                fn decode_atom(data: &[u8]) -> Self
+                where
+                    Self: Sized,
+                {
+                    <$ty>::from_be_bytes(data.try_into().expect(concat!(
+                        "key_format: malformed ",
+                        stringify!($ty),
+                        " input"
+                    )))
+                }
+            }
+        )*
+    };
+}
+
+impl_unsigned_atom!(u16, u32, u128);
+
+/// Implement `KeyFormatAtom` for signed integer types using big-endian encoding with the sign
+/// bit flipped, so that negative numbers are ordered before positive ones.
+macro_rules! impl_signed_atom {
+    ($($ty:ty => $uty:ty),*) => {
+        $(
+            impl KeyFormatAtom for $ty {
+                fn size() -> usize {
+                    size_of::<$ty>()
+                }
+
+                fn encode_atom(self) -> Vec<u8> {
+                    ((self as $uty) ^ (1 << (<$uty>::BITS - 1))).to_be_bytes().to_vec()
+                }
+
+                fn decode_atom(data: &[u8]) -> Self
+                where
+                    Self: Sized,
+                {
+                    let raw = <$uty>::from_be_bytes(data.try_into().expect(concat!(
+                        "key_format: malformed ",
+                        stringify!($ty),
+                        " input"
+                    )));
+                    (raw ^ (1 << (<$uty>::BITS - 1))) as $ty
+                }
+            }
+        )*
+    };
+}
+
+impl_signed_atom!(i8 => u8, i16 => u16, i32 => u32, i64 => u64, i128 => u128);
+
 impl KeyFormatAtom for () {
     fn size() -> usize {
         0
@@ -293,4 +356,89 @@ mod test {
 
         assert_eq!(dec, Some(key),)
     }
+
+    #[test]
+    fn test_key_format_atom_integers() {
+        fn roundtrip<T: KeyFormatAtom + Copy + PartialEq + std::fmt::Debug>(values: &[T]) {
+            for v in values {
+                let enc = v.encode_atom();
+                assert_eq!(enc.len(), T::size());
+                assert_eq!(T::decode_atom(&enc), *v);
+            }
+            // Encoded atoms must be ordered the same way as the (sorted) values.
+            for w in values.windows(2) {
+                assert!(w[0].encode_atom() < w[1].encode_atom());
+            }
+        }
+
+        roundtrip(&[0u16, 1, 255, 256, u16::MAX]);
+        roundtrip(&[0u32, 1, 255, 256, 65_536, u32::MAX]);
+        roundtrip(&[0u128, 1, u64::MAX as u128, u128::MAX]);
+        roundtrip(&[i8::MIN, -1, 0, 1, i8::MAX]);
+        roundtrip(&[i16::MIN, -256, -1, 0, 1, 256, i16::MAX]);
+        roundtrip(&[i32::MIN, -65_536, -1, 0, 1, 65_536, i32::MAX]);
+        roundtrip(&[
+            i64::MIN,
+            i32::MIN as i64,
+            -1,
+            0,
+            1,
+            i32::MAX as i64,
+            i64::MAX,
+        ]);
+        roundtrip(&[i128::MIN, -1, 0, 1, i128::MAX]);
+
+        assert_eq!(0i64.encode_atom(), vec![0x80, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!((-1i32).encode_atom(), vec![0x7f, 0xff, 0xff, 0xff]);
+    }
+
+    #[test]
+    fn test_key_format_atom_mkvs_order() {
+        use crate::storage::mkvs::{sync::NoopReadSyncer, Iterator, RootType, Tree};
+
+        key_format!(SignedKeyFormat, 0x01, i64);
+        key_format!(UnsignedKeyFormat, 0x02, u32);
+
+        let signed = [
+            i64::MIN,
+            -1_000_000,
+            -256,
+            -1,
+            0,
+            1,
+            255,
+            1_000_000,
+            i64::MAX,
+        ];
+        let unsigned = [0u32, 1, 255, 256, 65_535, 65_536, u32::MAX];
+
+        let mut tree = Tree::builder()
+            .with_root_type(RootType::State)
+            .build(Box::new(NoopReadSyncer));
+        // Insert in reverse order to make sure ordering comes from the encoding.
+        for v in signed.iter().rev() {
+            tree.insert(&SignedKeyFormat(*v).encode(), b"signed")
+                .unwrap();
+        }
+        for v in unsigned.iter().rev() {
+            tree.insert(&UnsignedKeyFormat(*v).encode(), b"unsigned")
+                .unwrap();
+        }
+
+        let mut it = tree.iter();
+        it.seek(&SignedKeyFormat::default().encode_partial(0));
+        let decoded: Vec<i64> = it
+            .by_ref()
+            .map_while(|(key, _)| SignedKeyFormat::decode(&key))
+            .map(|k| k.0)
+            .collect();
+        assert_eq!(decoded, signed);
+
+        it.seek(&UnsignedKeyFormat::default().encode_partial(0));
+        let decoded: Vec<u32> = it
+            .map_while(|(key, _)| UnsignedKeyFormat::decode(&key))
+            .map(|k| k.0)
+            .collect();
+        assert_eq!(decoded, unsigned);
+    }
 }
@@ -7,7 +7,7 @@ use thiserror::Error;
 #[cfg(test)]
 use crate::storage::mkvs::cache::CacheStats;
 use crate::storage::mkvs::{
-    cache::{Cache, CacheExtra, CacheItem, ReadSyncFetcher},
+    cache::{Cache, CacheExtra, CacheItem, FetchCounters, ReadSyncFetcher},
     sync::{merge_verified_subtree, ProofVerifier, ReadSync},
     tree::{
         Depth, InternalNode, Key, LeafNode, NodeBox, NodeKind, NodePointer, NodePtrRef, NodeRef,
@@ -156,6 +156,8 @@ pub struct LRUCache {
 
     lru_leaf: LRUList<NodePointer>,
     lru_internal: LRUList<NodePointer>,
+
+    counters: FetchCounters,
 }
 
 impl LRUCache {
@@ -186,6 +188,8 @@ impl LRUCache {
 
             lru_leaf: LRUList::new(value_capacity),
             lru_internal: LRUList::new(node_capacity),
+
+            counters: FetchCounters::default(),
         })
     }
 
@@ -362,6 +366,10 @@ impl Cache for LRUCache {
         &self.read_syncer
     }
 
+    fn fetch_counters(&self) -> FetchCounters {
+        self.counters
+    }
+
     fn new_internal_node(
         &mut self,
         label: &Key,
@@ -420,6 +428,7 @@ impl Cache for LRUCache {
                 drop(ptr);
                 self.remove_node(ptr_ref.clone());
             } else {
+                self.counters.cache_hits += 1;
                 return Ok(Some(node.clone()));
             }
         } else {
@@ -449,6 +458,13 @@ impl Cache for LRUCache {
 
     fn remote_sync<F: ReadSyncFetcher>(&mut self, ptr: NodePtrRef, fetcher: F) -> Result<()> {
         let proof = fetcher.fetch(self.sync_root, ptr.clone(), &mut self.read_syncer)?;
+        self.counters.fetches += 1;
+        self.counters.fetched_bytes += proof
+            .entries
+            .iter()
+            .flatten()
+            .map(|entry| entry.len())
+            .sum::<usize>();
 
         // The proof can be for one of two hashes: i) it is either for ptr.Hash in case
         // all the nodes are only contained in the subtree below ptr, or ii) it is for
@@ -22,6 +22,17 @@ pub struct CacheStats {
     pub leaf_value_size: usize,
 }
 
+/// Counters of node dereferences performed by the cache.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FetchCounters {
+    /// Number of nodes dereferenced without invoking the read syncer.
+    pub cache_hits: usize,
+    /// Number of proofs fetched via the read syncer.
+    pub fetches: usize,
+    /// Total size of proof entries fetched via the read syncer.
+    pub fetched_bytes: usize,
+}
+
 /// Used to fetch proofs from a remote tree via the ReadSyncer interface.
 pub trait ReadSyncFetcher {
     /// Fetch proof.
@@ -54,6 +65,9 @@ pub trait Cache {
     #[cfg(test)]
     fn get_read_syncer(&self) -> &Box<dyn ReadSync>;
 
+    /// Return the counters of node dereferences performed so far.
+    fn fetch_counters(&self) -> FetchCounters;
+
     /// Create a new internal node and returns a pointer to it.
     fn new_internal_node(
         &mut self,
@@ -0,0 +1,431 @@
+//! Self-describing container framing for serialized MKVS artifacts.
+//!
+//! A container consists of the magic bytes `MKVS`, a content type byte, a format version
+//! byte, the payload length as a big-endian `u32` and the payload itself. This makes it
+//! possible to tell artifacts apart when they are passed around as opaque byte strings.
+//!
+//! Streamed artifacts (write log entries and audit records) are written as a sequence of
+//! containers, one per item, and read back with `read_expecting`.
+use std::{
+    convert::{TryFrom, TryInto},
+    io::{self, Read},
+};
+
+use anyhow::Result;
+use thiserror::Error;
+
+use crate::storage::mkvs::{sync::Proof, WriteLog};
+
+/// Magic bytes at the start of every container.
+pub const MAGIC: [u8; 4] = *b"MKVS";
+
+/// Size of the container header.
+pub const HEADER_SIZE: usize = 10;
+
+/// Type of the content of a container.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ContentType {
+    /// A CBOR-encoded `Proof`.
+    Proof = 1,
+    /// A CBOR-encoded `WriteLog`.
+    WriteLog = 2,
+    /// A write log encoded by `encode_write_log_delta`.
+    WriteLogDelta = 3,
+    /// A session written by `RecordingMKVS::write_session`.
+    ReplaySession = 4,
+    /// A single CBOR-encoded `LogEntry` written by `CborWriterSink`.
+    WriteLogEntry = 5,
+    /// A single audit log record written by `AuditLog`.
+    AuditRecord = 6,
+}
+
+impl ContentType {
+    /// All content types.
+    pub const ALL: [ContentType; 6] = [
+        ContentType::Proof,
+        ContentType::WriteLog,
+        ContentType::WriteLogDelta,
+        ContentType::ReplaySession,
+        ContentType::WriteLogEntry,
+        ContentType::AuditRecord,
+    ];
+
+    /// Current format version of the content type.
+    pub fn version(self) -> u8 {
+        match self {
+            ContentType::Proof => 1,
+            ContentType::WriteLog => 1,
+            ContentType::WriteLogDelta => 1,
+            ContentType::ReplaySession => 1,
+            ContentType::WriteLogEntry => 1,
+            ContentType::AuditRecord => 1,
+        }
+    }
+}
+
+impl TryFrom<u8> for ContentType {
+    type Error = ContainerError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|&content_type| content_type as u8 == value)
+            .ok_or(ContainerError::UnknownContentType(value))
+    }
+}
+
+/// Errors returned when unwrapping a container.
+#[derive(Error, Clone, Debug, PartialEq, Eq)]
+pub enum ContainerError {
+    #[error("mkvs: not a container (bad magic)")]
+    BadMagic,
+    #[error("mkvs: truncated container header")]
+    TruncatedHeader,
+    #[error("mkvs: unknown container content type {0}")]
+    UnknownContentType(u8),
+    #[error("mkvs: expected {expected:?} container, found {found:?}")]
+    ContentTypeMismatch {
+        expected: ContentType,
+        found: ContentType,
+    },
+    #[error("mkvs: unsupported {content_type:?} container version {found} (expected {expected})")]
+    VersionMismatch {
+        content_type: ContentType,
+        expected: u8,
+        found: u8,
+    },
+    #[error("mkvs: container payload length {found} does not match header length {expected}")]
+    LengthMismatch { expected: usize, found: usize },
+}
+
+/// Wrap the given payload in a container of the given type, using its current version.
+///
+/// # Panics
+///
+/// Panics if the payload is larger than `u32::MAX` bytes.
+pub fn wrap(content_type: ContentType, payload: &[u8]) -> Vec<u8> {
+    let len: u32 = payload
+        .len()
+        .try_into()
+        .expect("mkvs: container payload too large");
+
+    let mut data = Vec::with_capacity(HEADER_SIZE + payload.len());
+    data.extend_from_slice(&MAGIC);
+    data.push(content_type as u8);
+    data.push(content_type.version());
+    data.extend_from_slice(&len.to_be_bytes());
+    data.extend_from_slice(payload);
+    data
+}
+
+/// Unwrap a container, checking that it has the expected type and current version.
+pub fn unwrap_expecting(data: &[u8], expected: ContentType) -> Result<&[u8], ContainerError> {
+    let len = check_header(data, expected)?;
+    let payload = &data[HEADER_SIZE..];
+    if payload.len() != len {
+        return Err(ContainerError::LengthMismatch {
+            expected: len,
+            found: payload.len(),
+        });
+    }
+    Ok(payload)
+}
+
+/// Read the next container from a stream of containers, checking that it has the expected
+/// type and current version.
+///
+/// Returns `None` if the stream ends at a container boundary. A partial header or payload is
+/// an error. The payload buffer grows with the data actually read, so a corrupted length
+/// cannot force a large allocation.
+pub fn read_expecting<R: Read>(reader: &mut R, expected: ContentType) -> Result<Option<Vec<u8>>> {
+    let mut header = [0u8; HEADER_SIZE];
+    match read_full(reader, &mut header)? {
+        0 => return Ok(None),
+        HEADER_SIZE => {}
+        _ => return Err(ContainerError::TruncatedHeader.into()),
+    }
+    let len = check_header(&header, expected)?;
+
+    let mut payload = Vec::new();
+    reader.take(len as u64).read_to_end(&mut payload)?;
+    if payload.len() != len {
+        return Err(ContainerError::LengthMismatch {
+            expected: len,
+            found: payload.len(),
+        }
+        .into());
+    }
+    Ok(Some(payload))
+}
+
+/// Check a container header, returning the payload length.
+fn check_header(data: &[u8], expected: ContentType) -> Result<usize, ContainerError> {
+    if data.len() < MAGIC.len() || data[..MAGIC.len()] != MAGIC {
+        return Err(ContainerError::BadMagic);
+    }
+    if data.len() < HEADER_SIZE {
+        return Err(ContainerError::TruncatedHeader);
+    }
+
+    let found = ContentType::try_from(data[4])?;
+    if found != expected {
+        return Err(ContainerError::ContentTypeMismatch { expected, found });
+    }
+    if data[5] != expected.version() {
+        return Err(ContainerError::VersionMismatch {
+            content_type: expected,
+            expected: expected.version(),
+            found: data[5],
+        });
+    }
+
+    Ok(u32::from_be_bytes(data[6..HEADER_SIZE].try_into().unwrap()) as usize)
+}
+
+/// Read into `buf` until it is full or the reader is exhausted, returning the number of bytes
+/// read.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(read)
+}
+
+/// Serialize a proof into a container.
+pub fn wrap_proof(proof: &Proof) -> Vec<u8> {
+    wrap(ContentType::Proof, &cbor::to_vec(proof.clone()))
+}
+
+/// Deserialize a proof from a container.
+pub fn unwrap_proof(data: &[u8]) -> Result<Proof> {
+    Ok(cbor::from_slice(unwrap_expecting(
+        data,
+        ContentType::Proof,
+    )?)?)
+}
+
+/// Serialize a write log into a container.
+pub fn wrap_write_log(write_log: &WriteLog) -> Vec<u8> {
+    wrap(ContentType::WriteLog, &cbor::to_vec(write_log.clone()))
+}
+
+/// Deserialize a write log from a container.
+pub fn unwrap_write_log(data: &[u8]) -> Result<WriteLog> {
+    Ok(cbor::from_slice(unwrap_expecting(
+        data,
+        ContentType::WriteLog,
+    )?)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        common::crypto::hash::Hash,
+        storage::mkvs::{
+            decode_write_log_delta, encode_write_log_delta, read_cbor_write_log, replay_session,
+            sync::NoopReadSyncer, verify_audit_log, CborWriterSink, LogEntry, OverlayTree,
+            RecordingMKVS, RootType, Tree, WriteLogSink, MKVS,
+        },
+    };
+
+    fn new_mkvs() -> OverlayTree<Tree> {
+        OverlayTree::new(
+            Tree::builder()
+                .with_root_type(RootType::State)
+                .build(Box::new(NoopReadSyncer)),
+        )
+    }
+
+    /// Unwrap the given data using the deserializer for the given content type.
+    fn unwrap_as(content_type: ContentType, data: &[u8]) -> Result<()> {
+        match content_type {
+            ContentType::Proof => unwrap_proof(data).map(|_| ()),
+            ContentType::WriteLog => unwrap_write_log(data).map(|_| ()),
+            ContentType::WriteLogDelta => decode_write_log_delta(data, |_| Ok(None)).map(|_| ()),
+            ContentType::ReplaySession => replay_session(data, &mut new_mkvs()).map(|_| ()),
+            ContentType::WriteLogEntry => read_cbor_write_log(data).map(|_| ()),
+            ContentType::AuditRecord => verify_audit_log(data, b"key").map(|_| ()),
+        }
+    }
+
+    #[test]
+    fn test_container_round_trip() {
+        for &content_type in &ContentType::ALL {
+            for payload in &[&b""[..], &b"payload"[..]] {
+                let data = wrap(content_type, payload);
+                assert_eq!(&data[..4], b"MKVS");
+                assert_eq!(unwrap_expecting(&data, content_type), Ok(*payload));
+            }
+        }
+    }
+
+    #[test]
+    fn test_container_stream() {
+        let mut stream = wrap(ContentType::WriteLogEntry, b"first");
+        stream.extend(wrap(ContentType::WriteLogEntry, b""));
+        stream.extend(wrap(ContentType::WriteLogEntry, b"third"));
+
+        let mut reader = &stream[..];
+        let mut read = || read_expecting(&mut reader, ContentType::WriteLogEntry).unwrap();
+        assert_eq!(read(), Some(b"first".to_vec()));
+        assert_eq!(read(), Some(b"".to_vec()));
+        assert_eq!(read(), Some(b"third".to_vec()));
+        assert_eq!(read(), None);
+
+        let read_err = |data: &[u8]| {
+            read_expecting(&mut &data[..], ContentType::WriteLogEntry)
+                .unwrap_err()
+                .downcast::<ContainerError>()
+                .unwrap()
+        };
+        assert_eq!(read_err(&stream[..3]), ContainerError::TruncatedHeader);
+        assert_eq!(
+            read_err(&stream[..HEADER_SIZE + 2]),
+            ContainerError::LengthMismatch {
+                expected: 5,
+                found: 2,
+            }
+        );
+        assert_eq!(
+            read_err(&wrap(ContentType::AuditRecord, b"first")),
+            ContainerError::ContentTypeMismatch {
+                expected: ContentType::WriteLogEntry,
+                found: ContentType::AuditRecord,
+            }
+        );
+
+        // A corrupted length must not be allocated upfront.
+        let mut oversized = wrap(ContentType::WriteLogEntry, b"first");
+        oversized[6..HEADER_SIZE].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert_eq!(
+            read_err(&oversized),
+            ContainerError::LengthMismatch {
+                expected: u32::MAX as usize,
+                found: 5,
+            }
+        );
+    }
+
+    #[test]
+    fn test_container_error_matrix() {
+        for &found in &ContentType::ALL {
+            let data = wrap(found, b"payload");
+            for &expected in &ContentType::ALL {
+                if expected == found {
+                    continue;
+                }
+                let mismatch = ContainerError::ContentTypeMismatch { expected, found };
+                assert_eq!(unwrap_expecting(&data, expected), Err(mismatch.clone()));
+
+                let err = unwrap_as(expected, &data).expect_err("unwrap should fail");
+                assert_eq!(err.downcast_ref::<ContainerError>(), Some(&mismatch));
+                assert_eq!(
+                    err.to_string(),
+                    format!("mkvs: expected {:?} container, found {:?}", expected, found)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_container_errors() {
+        let data = wrap(ContentType::WriteLog, b"payload");
+        let unwrap = |data: &[u8]| unwrap_expecting(data, ContentType::WriteLog).map(|_| ());
+
+        assert_eq!(unwrap(b""), Err(ContainerError::BadMagic));
+        assert_eq!(unwrap(b"payload"), Err(ContainerError::BadMagic));
+        assert_eq!(unwrap(&data[..8]), Err(ContainerError::TruncatedHeader));
+
+        let mut unknown = data.clone();
+        unknown[4] = 0xff;
+        assert_eq!(
+            unwrap(&unknown),
+            Err(ContainerError::UnknownContentType(0xff))
+        );
+
+        let mut future = data.clone();
+        future[5] = 2;
+        assert_eq!(
+            unwrap(&future),
+            Err(ContainerError::VersionMismatch {
+                content_type: ContentType::WriteLog,
+                expected: 1,
+                found: 2,
+            })
+        );
+
+        assert_eq!(
+            unwrap(&data[..data.len() - 1]),
+            Err(ContainerError::LengthMismatch {
+                expected: 7,
+                found: 6,
+            })
+        );
+        let mut trailing = data;
+        trailing.push(0);
+        assert_eq!(
+            unwrap(&trailing),
+            Err(ContainerError::LengthMismatch {
+                expected: 7,
+                found: 8,
+            })
+        );
+    }
+
+    #[test]
+    fn test_container_serializers() {
+        let write_log = vec![
+            LogEntry::new(b"foo", b"bar"),
+            LogEntry {
+                key: b"moo".to_vec(),
+                value: None,
+            },
+        ];
+        assert_eq!(
+            unwrap_write_log(&wrap_write_log(&write_log)).unwrap(),
+            write_log
+        );
+
+        let data = encode_write_log_delta(&write_log, |_| Ok(None)).unwrap();
+        assert!(unwrap_expecting(&data, ContentType::WriteLogDelta).is_ok());
+        assert_eq!(
+            decode_write_log_delta(&data, |_| Ok(None)).unwrap(),
+            write_log
+        );
+
+        let mut tree = Tree::builder()
+            .with_root_type(RootType::State)
+            .build(Box::new(NoopReadSyncer));
+        tree.insert(b"foo", b"bar").unwrap();
+        let root: Hash = tree.commit(Default::default(), 0).unwrap();
+        let proof = tree.get_proof(b"foo").unwrap().unwrap();
+        assert_eq!(proof.untrusted_root, root);
+        assert_eq!(unwrap_proof(&wrap_proof(&proof)).unwrap(), proof);
+
+        let mut sink = CborWriterSink::new(Vec::new());
+        for entry in &write_log {
+            sink.write(entry.clone()).unwrap();
+        }
+        let stream = sink.into_inner();
+        assert!(read_expecting(&mut &stream[..], ContentType::WriteLogEntry).is_ok());
+        assert_eq!(read_cbor_write_log(&stream[..]).unwrap(), write_log);
+
+        let mut recording = RecordingMKVS::new(new_mkvs());
+        recording.insert(b"foo", b"bar");
+        let mut session = Vec::new();
+        recording.write_session(&mut session).unwrap();
+        assert!(unwrap_expecting(&session, ContentType::ReplaySession).is_ok());
+        let report = replay_session(&session[..], &mut new_mkvs()).unwrap();
+        assert_eq!(report.calls, 1);
+        assert_eq!(report.divergence, None);
+    }
+}
@@ -0,0 +1,395 @@
+//! Compact write log encoding, storing values as deltas against their previous values.
+//!
+//! The encoding is wrapped in a `WriteLogDelta` container and consists of the number of
+//! entries followed by the entries themselves. Each entry consists of a kind byte, the key
+//! and, for inserts, either the full value or a delta against the previous value of the key.
+//! All lengths and offsets are encoded as LEB128 varints.
+//!
+//! A delta is a sequence of operations, each either copying a range of the previous value
+//! or inserting literal bytes.
+use std::convert::TryFrom;
+
+use anyhow::{anyhow, Result};
+
+use crate::storage::mkvs::{
+    container::{self, ContentType},
+    LogEntry, WriteLog,
+};
+
+/// Entry kinds.
+const ENTRY_DELETE: u8 = 0;
+const ENTRY_FULL: u8 = 1;
+const ENTRY_DELTA: u8 = 2;
+
+/// Delta operations.
+const OP_COPY: u8 = 0;
+const OP_INSERT: u8 = 1;
+
+/// Encode a write log, storing inserted values as deltas where that is smaller.
+///
+/// The `old_value` lookup must return the value of the given key before the write log was
+/// applied, e.g. by looking it up in the previous tree. The same values must be available
+/// when decoding.
+pub fn encode_write_log_delta<F>(write_log: &WriteLog, mut old_value: F) -> Result<Vec<u8>>
+where
+    F: FnMut(&[u8]) -> Result<Option<Vec<u8>>>,
+{
+    let mut data = Vec::new();
+    write_varint(&mut data, write_log.len() as u64);
+    for entry in write_log {
+        let value = match entry.value {
+            Some(ref value) => value,
+            None => {
+                data.push(ENTRY_DELETE);
+                write_bytes(&mut data, &entry.key);
+                continue;
+            }
+        };
+
+        let delta = old_value(&entry.key)?
+            .map(|old| diff(&old, value))
+            .filter(|delta| delta.len() < value.len());
+        match delta {
+            Some(delta) => {
+                data.push(ENTRY_DELTA);
+                write_bytes(&mut data, &entry.key);
+                write_bytes(&mut data, &delta);
+            }
+            None => {
+                data.push(ENTRY_FULL);
+                write_bytes(&mut data, &entry.key);
+                write_bytes(&mut data, value);
+            }
+        }
+    }
+    Ok(container::wrap(ContentType::WriteLogDelta, &data))
+}
+
+/// Decode a write log encoded by `encode_write_log_delta`.
+///
+/// The `old_value` lookup is only called for entries stored as deltas and must return the
+/// same values as during encoding.
+pub fn decode_write_log_delta<F>(data: &[u8], mut old_value: F) -> Result<WriteLog>
+where
+    F: FnMut(&[u8]) -> Result<Option<Vec<u8>>>,
+{
+    let mut reader = Reader {
+        data: container::unwrap_expecting(data, ContentType::WriteLogDelta)?,
+    };
+    let count = reader.read_varint()?;
+    let mut write_log = WriteLog::new();
+    for _ in 0..count {
+        let kind = reader.read_u8()?;
+        let key = reader.read_bytes()?.to_vec();
+        let value = match kind {
+            ENTRY_DELETE => None,
+            ENTRY_FULL => Some(reader.read_bytes()?.to_vec()),
+            ENTRY_DELTA => {
+                let delta = reader.read_bytes()?;
+                let old = old_value(&key)?
+                    .ok_or_else(|| anyhow!("mkvs: missing previous value for delta entry"))?;
+                Some(patch(&old, delta)?)
+            }
+            _ => return Err(anyhow!("mkvs: malformed delta write log entry")),
+        };
+        write_log.push(LogEntry { key, value });
+    }
+    if !reader.data.is_empty() {
+        return Err(anyhow!("mkvs: trailing data in delta write log"));
+    }
+    Ok(write_log)
+}
+
+/// Minimum length of an unchanged run worth a copy operation in the middle of a value.
+const MIN_COPY_LEN: usize = 4;
+
+/// Compute a delta transforming `old` into `new`.
+///
+/// The delta copies the common prefix and suffix of both values. If the rest of both values
+/// has the same length, unchanged runs in it are copied as well, otherwise it is inserted.
+/// This works well for small in-place changes such as counters.
+pub(crate) fn diff(old: &[u8], new: &[u8]) -> Vec<u8> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut delta = Vec::new();
+    if prefix > 0 {
+        delta.push(OP_COPY);
+        write_varint(&mut delta, 0);
+        write_varint(&mut delta, prefix as u64);
+    }
+    let old_middle = &old[prefix..old.len() - suffix];
+    let new_middle = &new[prefix..new.len() - suffix];
+    if old_middle.len() == new_middle.len() {
+        // Split the changed region into runs of equal and differing bytes, merging short
+        // equal runs into the surrounding inserts.
+        let mut start = 0;
+        let mut pos = 0;
+        while pos < new_middle.len() {
+            let run = old_middle[pos..]
+                .iter()
+                .zip(&new_middle[pos..])
+                .take_while(|(a, b)| a == b)
+                .count();
+            if run >= MIN_COPY_LEN {
+                if start < pos {
+                    delta.push(OP_INSERT);
+                    write_bytes(&mut delta, &new_middle[start..pos]);
+                }
+                delta.push(OP_COPY);
+                write_varint(&mut delta, (prefix + pos) as u64);
+                write_varint(&mut delta, run as u64);
+                start = pos + run;
+            }
+            pos += run.max(1);
+        }
+        if start < new_middle.len() {
+            delta.push(OP_INSERT);
+            write_bytes(&mut delta, &new_middle[start..]);
+        }
+    } else if !new_middle.is_empty() {
+        delta.push(OP_INSERT);
+        write_bytes(&mut delta, new_middle);
+    }
+    if suffix > 0 {
+        delta.push(OP_COPY);
+        write_varint(&mut delta, (old.len() - suffix) as u64);
+        write_varint(&mut delta, suffix as u64);
+    }
+    delta
+}
+
+/// Apply a delta produced by `diff` to `old`.
+pub(crate) fn patch(old: &[u8], delta: &[u8]) -> Result<Vec<u8>> {
+    let mut reader = Reader { data: delta };
+    let mut new = Vec::new();
+    while !reader.data.is_empty() {
+        match reader.read_u8()? {
+            OP_COPY => {
+                let offset = reader.read_varint()?;
+                let len = reader.read_varint()?;
+                let range = usize::try_from(offset)
+                    .ok()
+                    .zip(usize::try_from(len).ok())
+                    .and_then(|(offset, len)| old.get(offset..offset.checked_add(len)?))
+                    .ok_or_else(|| anyhow!("mkvs: delta copy out of range"))?;
+                new.extend_from_slice(range);
+            }
+            OP_INSERT => new.extend_from_slice(reader.read_bytes()?),
+            _ => return Err(anyhow!("mkvs: malformed delta operation")),
+        }
+    }
+    Ok(new)
+}
+
+fn write_varint(data: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        data.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    data.push(value as u8);
+}
+
+fn write_bytes(data: &mut Vec<u8>, bytes: &[u8]) {
+    write_varint(data, bytes.len() as u64);
+    data.extend_from_slice(bytes);
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn read_u8(&mut self) -> Result<u8> {
+        let (&byte, rest) = self
+            .data
+            .split_first()
+            .ok_or_else(|| anyhow!("mkvs: truncated delta write log"))?;
+        self.data = rest;
+        Ok(byte)
+    }
+
+    fn read_varint(&mut self) -> Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.read_u8()?;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(anyhow!("mkvs: malformed varint in delta write log"))
+    }
+
+    fn read_bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.read_varint()?;
+        let len = usize::try_from(len)
+            .ok()
+            .filter(|&len| len <= self.data.len())
+            .ok_or_else(|| anyhow!("mkvs: truncated delta write log"))?;
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+    use crate::storage::mkvs::{sync::NoopReadSyncer, RootType, Tree};
+
+    fn random_bytes(rng: &mut StdRng, max_len: usize) -> Vec<u8> {
+        let len = rng.gen_range(0..=max_len);
+        (0..len).map(|_| rng.gen_range(0..4)).collect()
+    }
+
+    /// Apply a few random edits to the given value.
+    fn mutate(rng: &mut StdRng, value: &[u8]) -> Vec<u8> {
+        let mut value = value.to_vec();
+        for _ in 0..rng.gen_range(0..4) {
+            let pos = rng.gen_range(0..=value.len());
+            match rng.gen_range(0..3) {
+                0 => value.insert(pos, rng.gen()),
+                1 if pos < value.len() => {
+                    value.remove(pos);
+                }
+                _ if pos < value.len() => value[pos] = rng.gen(),
+                _ => {}
+            }
+        }
+        value
+    }
+
+    #[test]
+    fn test_diff_round_trip() {
+        let cases: &[(&[u8], &[u8])] = &[
+            (b"", b""),
+            (b"", b"new"),
+            (b"old", b""),
+            (b"same", b"same"),
+            (b"balance: 100", b"balance: 101"),
+            (b"aaa", b"aaaa"),
+            (b"aaaa", b"aaa"),
+            (b"prefix middle suffix", b"prefix other suffix"),
+        ];
+        for (old, new) in cases {
+            assert_eq!(patch(old, &diff(old, new)).unwrap(), *new);
+        }
+        assert!(diff(b"same", b"same").len() < 4);
+    }
+
+    #[test]
+    fn test_diff_fuzz() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for _ in 0..10_000 {
+            let old = random_bytes(&mut rng, 64);
+            let new = if rng.gen() {
+                mutate(&mut rng, &old)
+            } else {
+                random_bytes(&mut rng, 64)
+            };
+            assert_eq!(patch(&old, &diff(&old, &new)).unwrap(), new);
+        }
+
+        // Arbitrary input must not cause a panic.
+        for _ in 0..10_000 {
+            let old = random_bytes(&mut rng, 16);
+            let delta: Vec<u8> = (0..rng.gen_range(0..32)).map(|_| rng.gen()).collect();
+            let _ = patch(&old, &delta);
+            let data = container::wrap(ContentType::WriteLogDelta, &delta);
+            let _ = decode_write_log_delta(&data, |_| Ok(Some(old.clone())));
+        }
+    }
+
+    #[test]
+    fn test_write_log_delta_round_trip() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let mut old = HashMap::new();
+        for i in 0..100 {
+            old.insert(
+                format!("key {}", i).into_bytes(),
+                random_bytes(&mut rng, 64),
+            );
+        }
+
+        let mut write_log = WriteLog::new();
+        for i in 0..150 {
+            let key = format!("key {}", i).into_bytes();
+            let value = match (old.get(&key), rng.gen_range(0..3)) {
+                (Some(_), 0) => None,
+                (Some(value), 1) => Some(mutate(&mut rng, value)),
+                _ => Some(random_bytes(&mut rng, 64)),
+            };
+            write_log.push(LogEntry { key, value });
+        }
+
+        let lookup = |key: &[u8]| Ok(old.get(key).cloned());
+        let data = encode_write_log_delta(&write_log, lookup).unwrap();
+        assert_eq!(decode_write_log_delta(&data, lookup).unwrap(), write_log);
+
+        // Deltas cannot be decoded without the previous values.
+        assert!(decode_write_log_delta(&data, |_| Ok(None)).is_err());
+        // Truncated data is rejected.
+        assert!(decode_write_log_delta(&data[..data.len() - 1], lookup).is_err());
+    }
+
+    #[test]
+    fn test_write_log_delta_counters() {
+        let balance = |i: usize, round: u64| {
+            format!(
+                "{{owner: {:064x}, balance: {:020}, nonce: {:08}}}",
+                i,
+                1_000_000 * i as u64 + round,
+                round
+            )
+        };
+
+        let mut tree = Tree::builder()
+            .with_root_type(RootType::State)
+            .build(Box::new(NoopReadSyncer));
+        for i in 0..1000 {
+            tree.insert(
+                format!("account {}", i).as_bytes(),
+                balance(i, 0).as_bytes(),
+            )
+            .unwrap();
+        }
+        tree.commit(Default::default(), 0).unwrap();
+
+        // The previous values are looked up in the tree before the update.
+        let write_log: WriteLog = (0..1000)
+            .map(|i| {
+                LogEntry::new(
+                    format!("account {}", i).as_bytes(),
+                    balance(i, 1).as_bytes(),
+                )
+            })
+            .collect();
+
+        let full = encode_write_log_delta(&write_log, |_| Ok(None)).unwrap();
+        let delta = encode_write_log_delta(&write_log, |key| tree.get(key)).unwrap();
+        assert!(
+            delta.len() * 2 < full.len(),
+            "delta size {} is not less than half of full size {}",
+            delta.len(),
+            full.len()
+        );
+        assert_eq!(
+            decode_write_log_delta(&full, |_| Ok(None)).unwrap(),
+            write_log
+        );
+        assert_eq!(
+            decode_write_log_delta(&delta, |key| tree.get(key)).unwrap(),
+            write_log
+        );
+    }
+}
@@ -1,26 +1,44 @@
 //! Merklized key-value store.
 use std::{
+    io::{self, Read, Write},
     iter,
     ops::{Deref, DerefMut},
 };
 
-use anyhow::{Error, Result};
+use anyhow::{anyhow, Error, Result};
 
 use crate::common::{crypto::hash::Hash, namespace::Namespace};
 
-use self::sync::Proof;
+use self::{container::ContentType, sync::Proof};
 
 #[macro_use]
 mod tree;
 mod cache;
+pub mod container;
+mod delta;
 #[cfg(test)]
 pub mod interop;
 pub mod marshal;
+mod replay;
 pub mod sync;
 #[cfg(test)]
 mod tests;
+mod typed;
 
-pub use tree::{Depth, Key, NodeBox, NodePointer, NodePtrRef, OverlayTree, Root, RootType, Tree};
+pub use delta::{decode_write_log_delta, encode_write_log_delta};
+pub use replay::{
+    replay_calls, replay_session, Divergence, RecordingMKVS, ReplayReport, SessionCall,
+    SessionMethod,
+};
+pub use tree::{
+    stateless_apply, storage_delta, verify_audit_log, verify_pair_proof, verify_write_log,
+    ApplyReport, AuditError, AuditLog, AuditOp, AuditRecord, AuditSummary, BalanceReport,
+    CommitStats, ConflictPolicy, Depth, HotKey, IterationCursor, Key, LogicalClock, MissingSubtree,
+    MonotonicClock, NibblePrefix, NodeBox, NodePointer, NodePtrRef, OverlayTree, ReadObservation,
+    Root, RootType, SalvageReport, SlowOp, SlowOpCallback, SlowOpRecord, SlowOpThresholds,
+    StorageDelta, TimeSource, Tree, TreeError, Witness, WriteConflict,
+};
+pub use typed::{CborCodec, Codec, TypedTrie};
 
 /// The type of entry in the log.
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
@@ -60,6 +78,73 @@ impl LogEntry {
 /// The keys in the write log must be unique.
 pub type WriteLog = Vec<LogEntry>;
 
+/// A sink receiving write log entries one at a time.
+pub trait WriteLogSink {
+    /// Write a single write log entry.
+    fn write(&mut self, entry: LogEntry) -> Result<()>;
+
+    /// Finish the write log, flushing any buffered entries.
+    fn finish(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl WriteLogSink for WriteLog {
+    fn write(&mut self, entry: LogEntry) -> Result<()> {
+        self.push(entry);
+        Ok(())
+    }
+}
+
+/// A write log sink which writes entries to an `io::Write` as they are received.
+///
+/// Each entry is written as a `WriteLogEntry` container holding the CBOR encoding of the
+/// entry, with no stream header or trailer, so the stream ends after the last entry. Use
+/// `read_cbor_write_log` to decode the resulting stream.
+pub struct CborWriterSink<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> CborWriterSink<W> {
+    /// Create a new sink writing to the given writer.
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    /// Consume the sink, returning the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write> WriteLogSink for CborWriterSink<W> {
+    fn write(&mut self, entry: LogEntry) -> Result<()> {
+        let data = cbor::to_vec(entry);
+        if data.len() > u32::MAX as usize {
+            return Err(anyhow!("mkvs: write log entry too large"));
+        }
+        self.writer
+            .write_all(&container::wrap(ContentType::WriteLogEntry, &data))?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Decode a write log written by `CborWriterSink`.
+///
+/// The stream must end at an entry boundary; a truncated entry is an error.
+pub fn read_cbor_write_log<R: Read>(mut reader: R) -> Result<WriteLog> {
+    let mut write_log = WriteLog::new();
+    while let Some(data) = container::read_expecting(&mut reader, ContentType::WriteLogEntry)? {
+        write_log.push(cbor::from_slice(&data)?);
+    }
+    Ok(write_log)
+}
+
 /// A key prefix.
 #[derive(Clone, Debug, Default, Eq, PartialEq, PartialOrd, Ord, cbor::Encode, cbor::Decode)]
 #[cbor(transparent)]
@@ -212,6 +297,11 @@ where
 }
 
 /// An MKVS iterator.
+///
+/// Iterators yield entries in lexicographic (byte-wise) key order. The order only depends on
+/// the logical contents of the tree: uncommitted inserts are merged into the same order,
+/// removed keys are skipped and the history of updates (including the order in which keys
+/// were inserted) has no effect on the output.
 pub trait Iterator: iter::Iterator<Item = (Vec<u8>, Vec<u8>)> {
     /// Sets the number of next elements to prefetch.
     fn set_prefetch(&mut self, prefetch: usize);
@@ -322,4 +412,82 @@ mod _tests {
 
         assert_eq!(write_log, deserialized);
     }
+
+    #[test]
+    fn test_write_log_streaming() {
+        let build_tree = || {
+            let mut tree = Tree::builder()
+                .with_root_type(RootType::State)
+                .build(Box::new(sync::NoopReadSyncer));
+            for i in 0..100 {
+                tree.insert(format!("key {}", i).as_bytes(), b"value")
+                    .unwrap();
+            }
+            tree.commit(Default::default(), 0).unwrap();
+            tree
+        };
+        let update = |overlay: &mut OverlayTree<&mut Tree>| {
+            for i in 0..10_000 {
+                overlay
+                    .insert(
+                        format!("new key {}", i).as_bytes(),
+                        &i.to_string().into_bytes(),
+                    )
+                    .unwrap();
+            }
+            for i in 0..50 {
+                overlay.remove(format!("key {}", i).as_bytes()).unwrap();
+            }
+        };
+
+        let mut tree = build_tree();
+        let mut overlay = OverlayTree::new(&mut tree);
+        update(&mut overlay);
+        let mut expected = overlay.commit().unwrap();
+
+        let mut tree = build_tree();
+        let mut overlay = OverlayTree::new(&mut tree);
+        update(&mut overlay);
+        let mut sink = CborWriterSink::new(Vec::new());
+        overlay.commit_streaming(&mut sink).unwrap();
+        let mut decoded = read_cbor_write_log(&sink.into_inner()[..]).unwrap();
+        assert_eq!(decoded.len(), 10_050);
+
+        // Inserts are ordered by key, removals are not ordered.
+        assert_eq!(decoded[..10_000], expected[..10_000]);
+        expected.sort_by(|a, b| a.key.cmp(&b.key));
+        decoded.sort_by(|a, b| a.key.cmp(&b.key));
+        assert_eq!(decoded, expected);
+    }
+
+    #[test]
+    fn test_write_log_streaming_truncated() {
+        // An empty stream is an empty write log.
+        assert!(read_cbor_write_log(&[][..]).unwrap().is_empty());
+
+        let data = container::wrap(ContentType::WriteLogEntry, &[0xa0]);
+        let read_err = |data: &[u8]| {
+            read_cbor_write_log(data)
+                .unwrap_err()
+                .downcast::<container::ContainerError>()
+                .unwrap()
+        };
+
+        // A partial header is an error.
+        assert_eq!(
+            read_err(&data[..2]),
+            container::ContainerError::TruncatedHeader
+        );
+
+        // A length larger than the remaining data is an error and must not be allocated upfront.
+        let mut oversized = data;
+        oversized[6..container::HEADER_SIZE].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert_eq!(
+            read_err(&oversized),
+            container::ContainerError::LengthMismatch {
+                expected: u32::MAX as usize,
+                found: 1,
+            }
+        );
+    }
 }
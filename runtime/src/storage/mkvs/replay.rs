@@ -0,0 +1,383 @@
+//! Recording and deterministic replay of MKVS sessions.
+use std::{
+    cell::RefCell,
+    convert::TryInto,
+    io::{Read, Write},
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    common::{crypto::hash::Hash, namespace::Namespace},
+    storage::mkvs::{
+        container::{self, ContentType},
+        sync::Proof,
+        Iterator, Prefix, WriteLog, MKVS,
+    },
+};
+
+/// A method recorded in a session.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, cbor::Encode, cbor::Decode)]
+#[repr(u8)]
+pub enum SessionMethod {
+    #[default]
+    Get = 0,
+    Insert = 1,
+    Remove = 2,
+    Commit = 3,
+}
+
+/// A single call recorded in a session.
+#[derive(Clone, Debug, Default, PartialEq, Eq, cbor::Encode, cbor::Decode)]
+pub struct SessionCall {
+    /// The called method.
+    pub method: SessionMethod,
+    /// The key argument (empty for commits).
+    pub key: Vec<u8>,
+    /// The value argument of inserts.
+    pub value: Option<Vec<u8>>,
+    /// The namespace argument of commits.
+    pub namespace: Namespace,
+    /// The version argument of commits.
+    pub version: u64,
+    /// The returned value, or its hash for redacted sessions. For commits this is the new
+    /// root hash.
+    pub result: Option<Vec<u8>>,
+    /// Whether the returned value has been replaced by its hash.
+    pub redacted: bool,
+}
+
+/// The first call whose result differs from the recorded one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Divergence {
+    /// Index of the call in the session.
+    pub index: usize,
+    /// The called method.
+    pub method: SessionMethod,
+    /// The recorded result.
+    pub expected: Option<Vec<u8>>,
+    /// The result of the replayed call, hashed in the same way as the recorded one.
+    pub actual: Option<Vec<u8>>,
+}
+
+/// Outcome of replaying a session.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReplayReport {
+    /// Number of calls replayed, including the diverging one.
+    pub calls: usize,
+    /// The first divergence, if any. Replay stops at the first divergence.
+    pub divergence: Option<Divergence>,
+}
+
+/// An MKVS wrapper which records all calls that read or modify entries.
+///
+/// Gets, inserts, removes and commits are recorded together with their results. Proofs,
+/// iteration, prefetching and cache queries are passed through without being recorded.
+pub struct RecordingMKVS<T: MKVS> {
+    inner: T,
+    calls: RefCell<Vec<SessionCall>>,
+    redact_values: bool,
+}
+
+impl<T: MKVS> RecordingMKVS<T> {
+    /// Create a new recording wrapper over the given MKVS.
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            calls: RefCell::new(Vec::new()),
+            redact_values: false,
+        }
+    }
+
+    /// Record hashes of returned values instead of the values themselves.
+    ///
+    /// Values passed to inserts are still recorded as they are needed for replay.
+    pub fn with_redacted_values(mut self, redact_values: bool) -> Self {
+        self.redact_values = redact_values;
+        self
+    }
+
+    /// Return the calls recorded so far.
+    pub fn calls(&self) -> Vec<SessionCall> {
+        self.calls.borrow().clone()
+    }
+
+    /// Write the recorded session to the given writer.
+    ///
+    /// The session is written as a `ReplaySession` container holding each call as a
+    /// big-endian `u32` length followed by its CBOR encoding. Use `replay_session` to replay
+    /// the resulting session.
+    pub fn write_session<W: Write>(&self, mut writer: W) -> Result<()> {
+        let mut payload = Vec::new();
+        for call in self.calls.borrow().iter() {
+            let data = cbor::to_vec(call.clone());
+            let len: u32 = data
+                .len()
+                .try_into()
+                .map_err(|_| anyhow!("mkvs: session call too large"))?;
+            payload.extend_from_slice(&len.to_be_bytes());
+            payload.extend_from_slice(&data);
+        }
+        writer.write_all(&container::wrap(ContentType::ReplaySession, &payload))?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Consume the wrapper, returning the underlying MKVS.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    fn record(
+        &self,
+        method: SessionMethod,
+        key: &[u8],
+        value: Option<&[u8]>,
+        result: Option<&[u8]>,
+    ) {
+        self.calls.borrow_mut().push(SessionCall {
+            method,
+            key: key.to_vec(),
+            value: value.map(|value| value.to_vec()),
+            result: encode_result(result, self.redact_values),
+            redacted: self.redact_values,
+            ..Default::default()
+        });
+    }
+}
+
+impl<T: MKVS> MKVS for RecordingMKVS<T> {
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let result = self.inner.get(key);
+        self.record(SessionMethod::Get, key, None, result.as_deref());
+        result
+    }
+
+    fn get_proof(&self, key: &[u8]) -> Option<Proof> {
+        self.inner.get_proof(key)
+    }
+
+    fn cache_contains_key(&self, key: &[u8]) -> bool {
+        self.inner.cache_contains_key(key)
+    }
+
+    fn insert(&mut self, key: &[u8], value: &[u8]) -> Option<Vec<u8>> {
+        let result = self.inner.insert(key, value);
+        self.record(SessionMethod::Insert, key, Some(value), result.as_deref());
+        result
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        let result = self.inner.remove(key);
+        self.record(SessionMethod::Remove, key, None, result.as_deref());
+        result
+    }
+
+    fn prefetch_prefixes(&self, prefixes: &[Prefix], limit: u16) {
+        self.inner.prefetch_prefixes(prefixes, limit)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator + '_> {
+        self.inner.iter()
+    }
+
+    fn commit(&mut self, namespace: Namespace, version: u64) -> Result<(WriteLog, Hash)> {
+        let (write_log, root) = self.inner.commit(namespace, version)?;
+        self.calls.borrow_mut().push(SessionCall {
+            method: SessionMethod::Commit,
+            namespace,
+            version,
+            result: Some(root.as_ref().to_vec()),
+            ..Default::default()
+        });
+        Ok((write_log, root))
+    }
+}
+
+/// Replay a session written by `RecordingMKVS::write_session` against the given MKVS.
+///
+/// The calls are re-issued in order and their results are compared with the recorded ones,
+/// stopping at the first divergence.
+pub fn replay_session<R: Read>(mut reader: R, mkvs: &mut dyn MKVS) -> Result<ReplayReport> {
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data)?;
+    let mut payload = container::unwrap_expecting(&data, ContentType::ReplaySession)?;
+
+    let mut calls = Vec::new();
+    while !payload.is_empty() {
+        if payload.len() < 4 {
+            return Err(anyhow!("mkvs: truncated session"));
+        }
+        let (len, rest) = payload.split_at(4);
+        let len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+        if rest.len() < len {
+            return Err(anyhow!("mkvs: truncated session"));
+        }
+        let (call, rest) = rest.split_at(len);
+        calls.push(cbor::from_slice(call)?);
+        payload = rest;
+    }
+    replay_calls(calls, mkvs)
+}
+
+/// Replay the given calls against the given MKVS, stopping at the first divergence.
+pub fn replay_calls(calls: Vec<SessionCall>, mkvs: &mut dyn MKVS) -> Result<ReplayReport> {
+    let mut report = ReplayReport::default();
+    for call in calls {
+        let actual = replay_call(&call, mkvs)?;
+        report.calls += 1;
+        if actual != call.result {
+            report.divergence = Some(Divergence {
+                index: report.calls - 1,
+                method: call.method,
+                expected: call.result,
+                actual,
+            });
+            break;
+        }
+    }
+    Ok(report)
+}
+
+fn replay_call(call: &SessionCall, mkvs: &mut dyn MKVS) -> Result<Option<Vec<u8>>> {
+    let result = match call.method {
+        SessionMethod::Get => mkvs.get(&call.key),
+        SessionMethod::Insert => {
+            let value = call
+                .value
+                .as_ref()
+                .ok_or_else(|| anyhow!("mkvs: session insert without a value"))?;
+            mkvs.insert(&call.key, value)
+        }
+        SessionMethod::Remove => mkvs.remove(&call.key),
+        SessionMethod::Commit => {
+            let (_, root) = mkvs.commit(call.namespace, call.version)?;
+            return Ok(Some(root.as_ref().to_vec()));
+        }
+    };
+    Ok(encode_result(result.as_deref(), call.redacted))
+}
+
+fn encode_result(result: Option<&[u8]>, redact: bool) -> Option<Vec<u8>> {
+    result.map(|value| {
+        if redact {
+            Hash::digest_bytes(value).as_ref().to_vec()
+        } else {
+            value.to_vec()
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+    use crate::storage::mkvs::{sync::NoopReadSyncer, OverlayTree, RootType, Tree};
+
+    const KEYS: usize = 30;
+
+    fn new_mkvs(changed_key: Option<usize>) -> OverlayTree<Tree> {
+        let mut tree = Tree::builder()
+            .with_root_type(RootType::State)
+            .build(Box::new(NoopReadSyncer));
+        for i in 0..KEYS / 2 {
+            let value = if changed_key == Some(i) {
+                b"changed".to_vec()
+            } else {
+                format!("value {}", i).into_bytes()
+            };
+            tree.insert(format!("key {}", i).as_bytes(), &value)
+                .unwrap();
+        }
+        tree.commit(Default::default(), 0).unwrap();
+        OverlayTree::new(tree)
+    }
+
+    fn record_session(redact_values: bool) -> RecordingMKVS<OverlayTree<Tree>> {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut mkvs = RecordingMKVS::new(new_mkvs(None)).with_redacted_values(redact_values);
+        for i in 0..200 {
+            let key = format!("key {}", rng.gen_range(0..KEYS)).into_bytes();
+            match rng.gen_range(0..3) {
+                0 => {
+                    mkvs.get(&key);
+                }
+                1 => {
+                    mkvs.insert(&key, format!("new value {}", i).as_bytes());
+                }
+                _ => {
+                    mkvs.remove(&key);
+                }
+            }
+            if i % 50 == 49 {
+                mkvs.commit(Default::default(), i / 50 + 1).unwrap();
+            }
+        }
+        mkvs
+    }
+
+    fn replay(recording: &RecordingMKVS<OverlayTree<Tree>>, mkvs: &mut dyn MKVS) -> ReplayReport {
+        let mut session = Vec::new();
+        recording.write_session(&mut session).unwrap();
+        replay_session(&session[..], mkvs).unwrap()
+    }
+
+    /// Index of the first call which observes the changed key or commits.
+    fn first_divergence(calls: &[SessionCall], changed_key: usize) -> usize {
+        let key = format!("key {}", changed_key).into_bytes();
+        calls
+            .iter()
+            .position(|call| call.method == SessionMethod::Commit || call.key == key)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_replay_identical() {
+        for &redact_values in &[false, true] {
+            let recording = record_session(redact_values);
+            let report = replay(&recording, &mut new_mkvs(None));
+            assert_eq!(report.calls, 204);
+            assert_eq!(report.divergence, None);
+        }
+    }
+
+    #[test]
+    fn test_replay_divergence() {
+        let recording = record_session(false);
+        let calls = recording.calls();
+        for changed_key in 0..KEYS / 2 {
+            let index = first_divergence(&calls, changed_key);
+            let report = replay(&recording, &mut new_mkvs(Some(changed_key)));
+            assert_eq!(report.calls, index + 1);
+
+            let divergence = report.divergence.expect("divergence");
+            assert_eq!(divergence.index, index);
+            assert_eq!(divergence.method, calls[index].method);
+            assert_eq!(divergence.expected, calls[index].result);
+            if divergence.method != SessionMethod::Commit {
+                assert_eq!(divergence.actual, Some(b"changed".to_vec()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_replay_divergence_redacted() {
+        let recording = record_session(true);
+        let calls = recording.calls();
+        let index = first_divergence(&calls, 3);
+        assert_ne!(calls[index].method, SessionMethod::Commit);
+        assert!(calls
+            .iter()
+            .flat_map(|call| &call.result)
+            .all(|result| result.len() == 32));
+
+        let report = replay(&recording, &mut new_mkvs(Some(3)));
+        let divergence = report.divergence.expect("divergence");
+        assert_eq!(divergence.index, index);
+        assert_eq!(
+            divergence.actual,
+            Some(Hash::digest_bytes(b"changed").as_ref().to_vec())
+        );
+    }
+}
@@ -144,11 +144,12 @@ impl ProofBuilder {
         // For internal nodes, also include children.
         if let NodeBox::Internal(nd) = node {
             fn get_child_hash(nd: &Rc<RefCell<NodePointer>>) -> Hash {
-                nd.borrow()
-                    .node
+                // Children which are not available locally are included by their hash.
+                let ptr = nd.borrow();
+                ptr.node
                     .as_ref()
                     .map(|n| n.borrow().get_hash())
-                    .unwrap_or_else(Hash::empty_hash)
+                    .unwrap_or(ptr.hash)
             }
 
             if self.proof_version == 1 {
@@ -0,0 +1,401 @@
+//! HMAC-chained audit log of mutating tree operations.
+//!
+//! Each successful insert, remove and commit appends a fixed-size record, wrapped in an
+//! `AuditRecord` container, to the configured sink. Every record carries a MAC over its
+//! contents and the MAC of the previous record, so modified, reordered or dropped records are
+//! detected by `verify_audit_log`.
+use std::{
+    convert::TryInto,
+    io::{self, Read, Write},
+};
+
+use anyhow::Result;
+use hmac::{Hmac, Mac};
+use sha2::Sha512_256;
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+use crate::{
+    common::crypto::hash::Hash,
+    storage::mkvs::container::{self, ContentType},
+};
+
+type AuditMac = Hmac<Sha512_256>;
+
+/// Size of the audit record MAC.
+const AUDIT_MAC_SIZE: usize = 32;
+/// Size of an encoded audit record.
+const AUDIT_RECORD_SIZE: usize = 8 + 1 + Hash::len() + Hash::len() + AUDIT_MAC_SIZE;
+
+/// Audit log errors.
+#[derive(Error, Debug)]
+pub enum AuditError {
+    #[error("mkvs/audit: malformed record at sequence {0}")]
+    MalformedRecord(u64),
+    #[error("mkvs/audit: unexpected sequence (expected {expected} got {got})")]
+    UnexpectedSequence { expected: u64, got: u64 },
+    #[error("mkvs/audit: unknown operation {0} at sequence {1}")]
+    UnknownOperation(u8, u64),
+    #[error("mkvs/audit: invalid MAC at sequence {0}")]
+    InvalidMac(u64),
+    #[error("mkvs/audit: failed to write record {sequence} of an applied operation: {source}")]
+    WriteFailed {
+        sequence: u64,
+        #[source]
+        source: io::Error,
+    },
+}
+
+/// Type of a mutating operation recorded in the audit log.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum AuditOp {
+    Insert = 0x01,
+    Remove = 0x02,
+    Commit = 0x03,
+}
+
+impl AuditOp {
+    fn from_u8(op: u8) -> Option<AuditOp> {
+        match op {
+            0x01 => Some(AuditOp::Insert),
+            0x02 => Some(AuditOp::Remove),
+            0x03 => Some(AuditOp::Commit),
+            _ => None,
+        }
+    }
+}
+
+/// A single audit log record.
+///
+/// Keys and values are never recorded directly, only their hashes. For commits, the key hash
+/// is the empty hash and the value hash is the new root hash.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AuditRecord {
+    pub sequence: u64,
+    pub op: AuditOp,
+    pub key_hash: Hash,
+    pub value_hash: Hash,
+    pub mac: [u8; AUDIT_MAC_SIZE],
+}
+
+impl AuditRecord {
+    fn compute_mac(
+        key: &[u8],
+        prev_mac: &[u8; AUDIT_MAC_SIZE],
+        sequence: u64,
+        op: u8,
+        key_hash: &Hash,
+        value_hash: &Hash,
+    ) -> AuditMac {
+        let mut mac = AuditMac::new_from_slice(key).expect("Hmac::new_from_slice");
+        mac.update(prev_mac);
+        mac.update(&sequence.to_be_bytes());
+        mac.update(&[op]);
+        mac.update(key_hash.as_ref());
+        mac.update(value_hash.as_ref());
+        mac
+    }
+
+    fn encode(&self) -> [u8; AUDIT_RECORD_SIZE] {
+        let mut data = [0u8; AUDIT_RECORD_SIZE];
+        data[..8].copy_from_slice(&self.sequence.to_be_bytes());
+        data[8] = self.op as u8;
+        data[9..41].copy_from_slice(self.key_hash.as_ref());
+        data[41..73].copy_from_slice(self.value_hash.as_ref());
+        data[73..].copy_from_slice(&self.mac);
+        data
+    }
+}
+
+/// Summary of a verified audit log.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AuditSummary {
+    /// Number of records in the log.
+    pub records: u64,
+    /// Number of records per operation type (insert, remove, commit).
+    pub inserts: u64,
+    pub removes: u64,
+    pub commits: u64,
+    /// MAC of the last record in the log (all zeroes for an empty log).
+    ///
+    /// Since dropping records from the end of the log cannot be detected from the chain
+    /// itself, callers should compare this against an externally kept checkpoint.
+    pub head_mac: [u8; AUDIT_MAC_SIZE],
+}
+
+/// An HMAC-chained, tamper-evident log of mutating tree operations.
+///
+/// Each record is authenticated with a MAC over the record contents and the MAC of the
+/// previous record, keyed by an operator-supplied audit key.
+pub struct AuditLog {
+    key: Zeroizing<Vec<u8>>,
+    sink: Box<dyn Write + Send>,
+    sequence: u64,
+    prev_mac: [u8; AUDIT_MAC_SIZE],
+}
+
+impl AuditLog {
+    /// Create a new audit log writing records to the given sink.
+    pub fn new(key: Zeroizing<Vec<u8>>, sink: Box<dyn Write + Send>) -> Self {
+        Self {
+            key,
+            sink,
+            sequence: 0,
+            prev_mac: [0; AUDIT_MAC_SIZE],
+        }
+    }
+
+    /// Append a record to the log and flush the sink.
+    ///
+    /// Fails with `AuditError::WriteFailed` if the sink fails.
+    pub(super) fn append(&mut self, op: AuditOp, key_hash: Hash, value_hash: Hash) -> Result<()> {
+        let mac = AuditRecord::compute_mac(
+            &self.key,
+            &self.prev_mac,
+            self.sequence,
+            op as u8,
+            &key_hash,
+            &value_hash,
+        );
+        let record = AuditRecord {
+            sequence: self.sequence,
+            op,
+            key_hash,
+            value_hash,
+            mac: mac.finalize().into_bytes().into(),
+        };
+
+        self.sink
+            .write_all(&container::wrap(ContentType::AuditRecord, &record.encode()))
+            .and_then(|_| self.sink.flush())
+            .map_err(|source| AuditError::WriteFailed {
+                sequence: self.sequence,
+                source,
+            })?;
+
+        self.sequence += 1;
+        self.prev_mac = record.mac;
+
+        Ok(())
+    }
+}
+
+/// Verify the integrity of an audit log produced by `AuditLog`.
+///
+/// Fails if any record was modified, reordered or dropped (except at the end of the log, see
+/// `AuditSummary::head_mac`).
+pub fn verify_audit_log<R: Read>(mut reader: R, key: &[u8]) -> Result<AuditSummary> {
+    let mut summary = AuditSummary {
+        records: 0,
+        inserts: 0,
+        removes: 0,
+        commits: 0,
+        head_mac: [0; AUDIT_MAC_SIZE],
+    };
+
+    while let Some(data) = container::read_expecting(&mut reader, ContentType::AuditRecord)? {
+        if data.len() != AUDIT_RECORD_SIZE {
+            return Err(AuditError::MalformedRecord(summary.records).into());
+        }
+
+        let sequence = u64::from_be_bytes(data[..8].try_into().unwrap());
+        if sequence != summary.records {
+            return Err(AuditError::UnexpectedSequence {
+                expected: summary.records,
+                got: sequence,
+            }
+            .into());
+        }
+        let op =
+            AuditOp::from_u8(data[8]).ok_or(AuditError::UnknownOperation(data[8], sequence))?;
+        let key_hash = Hash::from(&data[9..41]);
+        let value_hash = Hash::from(&data[41..73]);
+
+        AuditRecord::compute_mac(
+            key,
+            &summary.head_mac,
+            sequence,
+            op as u8,
+            &key_hash,
+            &value_hash,
+        )
+        .verify_slice(&data[73..])
+        .map_err(|_| AuditError::InvalidMac(sequence))?;
+
+        match op {
+            AuditOp::Insert => summary.inserts += 1,
+            AuditOp::Remove => summary.removes += 1,
+            AuditOp::Commit => summary.commits += 1,
+        }
+        summary.records += 1;
+        summary.head_mac.copy_from_slice(&data[73..]);
+    }
+
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::storage::mkvs::{sync::NoopReadSyncer, Root, RootType, Tree};
+
+    #[derive(Clone, Default)]
+    struct SharedSink(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedSink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    const AUDIT_KEY: &[u8] = b"audit key";
+    /// Size of an audit record including its container header.
+    const FRAMED_RECORD_SIZE: usize = container::HEADER_SIZE + AUDIT_RECORD_SIZE;
+
+    fn generate_log() -> Vec<u8> {
+        let sink = SharedSink::default();
+        let mut tree = Tree::builder()
+            .with_root_type(RootType::State)
+            .with_audit_log(Zeroizing::new(AUDIT_KEY.to_vec()), Box::new(sink.clone()))
+            .build(Box::new(NoopReadSyncer));
+
+        tree.insert(b"foo", b"bar").expect("insert");
+        tree.insert(b"moo", b"boo").expect("insert");
+        tree.remove(b"foo").expect("remove");
+        tree.commit(Default::default(), 1).expect("commit");
+
+        let log = sink.0.lock().unwrap().clone();
+        log
+    }
+
+    #[test]
+    fn test_audit_log() {
+        let log = generate_log();
+        assert_eq!(log.len(), 4 * FRAMED_RECORD_SIZE);
+
+        let summary = verify_audit_log(&log[..], AUDIT_KEY).expect("audit log should verify");
+        assert_eq!(summary.records, 4);
+        assert_eq!(summary.inserts, 2);
+        assert_eq!(summary.removes, 1);
+        assert_eq!(summary.commits, 1);
+        assert_eq!(&summary.head_mac[..], &log[log.len() - AUDIT_MAC_SIZE..]);
+
+        // Empty log.
+        let summary = verify_audit_log(&[][..], AUDIT_KEY).expect("empty log should verify");
+        assert_eq!(summary.records, 0);
+
+        // Wrong key.
+        assert!(verify_audit_log(&log[..], b"wrong key").is_err());
+    }
+
+    #[test]
+    fn test_audit_log_failed_operations() {
+        let sink = SharedSink::default();
+        let mut tree = Tree::builder()
+            .with_root(Root {
+                root_type: RootType::State,
+                hash: Hash::digest_bytes(b"remote root"),
+                ..Default::default()
+            })
+            .with_audit_log(Zeroizing::new(AUDIT_KEY.to_vec()), Box::new(sink.clone()))
+            .build(Box::new(NoopReadSyncer));
+
+        // Operations which fail to fetch the remote root must not be recorded.
+        assert!(tree.insert(b"foo", b"bar").is_err());
+        assert!(tree.remove(b"foo").is_err());
+        assert!(sink.0.lock().unwrap().is_empty());
+    }
+
+    struct FailingSink;
+
+    impl Write for FailingSink {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::Error::other("sink failed"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_audit_log_write_failure() {
+        let mut tree = Tree::builder()
+            .with_root_type(RootType::State)
+            .with_audit_log(Zeroizing::new(AUDIT_KEY.to_vec()), Box::new(FailingSink))
+            .build(Box::new(NoopReadSyncer));
+
+        // The operation is applied even though it could not be recorded.
+        let err = tree.insert(b"foo", b"bar").expect_err("insert should fail");
+        match err.downcast_ref::<AuditError>() {
+            Some(AuditError::WriteFailed { sequence: 0, .. }) => {}
+            _ => panic!("unexpected error: {:?}", err),
+        }
+        assert_eq!(tree.get(b"foo").expect("get"), Some(b"bar".to_vec()));
+    }
+
+    #[test]
+    fn test_audit_log_tampering() {
+        let log = generate_log();
+
+        // Modified record.
+        for offset in [0, 8, 9, 41, 73] {
+            let mut tampered = log.clone();
+            tampered[FRAMED_RECORD_SIZE + container::HEADER_SIZE + offset] ^= 0x01;
+            assert!(
+                verify_audit_log(&tampered[..], AUDIT_KEY).is_err(),
+                "modified record at offset {} should fail verification",
+                offset
+            );
+        }
+
+        // Reordered records.
+        let mut tampered = log.clone();
+        let (first, rest) = tampered.split_at_mut(FRAMED_RECORD_SIZE);
+        first.swap_with_slice(&mut rest[..FRAMED_RECORD_SIZE]);
+        assert!(verify_audit_log(&tampered[..], AUDIT_KEY).is_err());
+
+        // Dropped record.
+        let mut tampered = log.clone();
+        tampered.drain(FRAMED_RECORD_SIZE..2 * FRAMED_RECORD_SIZE);
+        assert!(verify_audit_log(&tampered[..], AUDIT_KEY).is_err());
+
+        // Dropped record with renumbered sequence, so only the chain detects it.
+        let mut tampered = log.clone();
+        tampered.drain(FRAMED_RECORD_SIZE..2 * FRAMED_RECORD_SIZE);
+        for (seq, record) in tampered.chunks_mut(FRAMED_RECORD_SIZE).enumerate() {
+            record[container::HEADER_SIZE..container::HEADER_SIZE + 8]
+                .copy_from_slice(&(seq as u64).to_be_bytes());
+        }
+        assert!(verify_audit_log(&tampered[..], AUDIT_KEY).is_err());
+
+        // Truncated record.
+        assert!(verify_audit_log(&log[..log.len() - 1], AUDIT_KEY).is_err());
+
+        // Record framed as a different content type.
+        let mut tampered = log.clone();
+        tampered[FRAMED_RECORD_SIZE + 4] = ContentType::WriteLogEntry as u8;
+        assert!(verify_audit_log(&tampered[..], AUDIT_KEY).is_err());
+
+        // Record with a bad payload length.
+        let payload = vec![0u8; AUDIT_RECORD_SIZE - 1];
+        let tampered = container::wrap(ContentType::AuditRecord, &payload);
+        let err = verify_audit_log(&tampered[..], AUDIT_KEY).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<AuditError>(),
+            Some(AuditError::MalformedRecord(0))
+        ));
+
+        // A valid prefix of the log verifies, but with a different head.
+        let summary =
+            verify_audit_log(&log[..2 * FRAMED_RECORD_SIZE], AUDIT_KEY).expect("prefix verifies");
+        assert_ne!(&summary.head_mac[..], &log[log.len() - AUDIT_MAC_SIZE..]);
+    }
+}
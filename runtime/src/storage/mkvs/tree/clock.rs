@@ -0,0 +1,157 @@
+//! Time sources for time-dependent tree behavior.
+//!
+//! All time-dependent behavior of the tree must go through a `TimeSource` so that it stays
+//! deterministic when replayed and inside enclaves.
+use std::{
+    convert::TryFrom,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// A source of monotonic time.
+pub trait TimeSource: Send {
+    /// Return the current time, measured from an arbitrary but fixed origin.
+    fn now(&self) -> Duration;
+}
+
+/// A time source backed by the system's monotonic clock.
+pub struct MonotonicClock {
+    origin: std::time::Instant,
+}
+
+impl MonotonicClock {
+    /// Create a new monotonic clock with its origin at the current instant.
+    pub fn new() -> Self {
+        Self {
+            origin: std::time::Instant::now(),
+        }
+    }
+}
+
+impl Default for MonotonicClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TimeSource for MonotonicClock {
+    fn now(&self) -> Duration {
+        self.origin.elapsed()
+    }
+}
+
+/// A logical clock which only advances when driven by the caller.
+///
+/// Clones share the same time, so a clone can be kept to drive a clock installed in a tree.
+#[derive(Clone, Debug, Default)]
+pub struct LogicalClock {
+    nanos: Arc<AtomicU64>,
+}
+
+impl LogicalClock {
+    /// Create a new logical clock starting at zero.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Advance the clock by the given duration, saturating at the largest representable time.
+    pub fn advance(&self, by: Duration) {
+        let by = u64::try_from(by.as_nanos()).unwrap_or(u64::MAX);
+        let _ = self
+            .nanos
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |nanos| {
+                Some(nanos.saturating_add(by))
+            });
+    }
+}
+
+impl TimeSource for LogicalClock {
+    fn now(&self) -> Duration {
+        Duration::from_nanos(self.nanos.load(Ordering::SeqCst))
+    }
+}
+
+/// Return the default time source.
+///
+/// Inside enclaves this is a logical clock that never advances unless driven by the caller.
+pub(super) fn default_time_source() -> Box<dyn TimeSource> {
+    #[cfg(not(target_env = "sgx"))]
+    {
+        Box::new(MonotonicClock::new())
+    }
+    #[cfg(target_env = "sgx")]
+    {
+        Box::new(LogicalClock::new())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, path::Path};
+
+    use super::*;
+
+    /// Calls which must not be used by the module outside of this file.
+    const DENIED_CALLS: &[&str] = &[
+        "Instant::now",
+        "SystemTime::now",
+        "thread_rng",
+        "rand::random",
+        "OsRng",
+    ];
+
+    fn check_dir(dir: &Path, violations: &mut Vec<String>) {
+        for entry in fs::read_dir(dir).expect("read_dir") {
+            let path = entry.expect("dir entry").path();
+            if path.is_dir() {
+                check_dir(&path, violations);
+                continue;
+            }
+            if path.extension().and_then(|ext| ext.to_str()) != Some("rs")
+                || path.ends_with("tree/clock.rs")
+            {
+                continue;
+            }
+            let source = fs::read_to_string(&path).expect("read source");
+            for (line, text) in source.lines().enumerate() {
+                for call in DENIED_CALLS {
+                    if text.contains(call) {
+                        violations.push(format!("{}:{}: {}", path.display(), line + 1, call));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_no_direct_time_or_entropy() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/storage/mkvs");
+        let mut violations = Vec::new();
+        check_dir(&dir, &mut violations);
+        assert!(
+            violations.is_empty(),
+            "time and entropy must go through a TimeSource:\n{}",
+            violations.join("\n")
+        );
+    }
+
+    #[test]
+    fn test_logical_clock() {
+        let clock = LogicalClock::new();
+        let shared = clock.clone();
+        assert_eq!(clock.now(), Duration::ZERO);
+
+        shared.advance(Duration::from_millis(5));
+        shared.advance(Duration::from_millis(10));
+        assert_eq!(clock.now(), Duration::from_millis(15));
+
+        // Large durations saturate instead of wrapping around.
+        shared.advance(Duration::MAX);
+        assert_eq!(clock.now(), Duration::from_nanos(u64::MAX));
+        shared.advance(Duration::from_millis(5));
+        assert_eq!(clock.now(), Duration::from_nanos(u64::MAX));
+    }
+}
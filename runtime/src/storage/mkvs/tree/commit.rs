@@ -4,19 +4,63 @@ use crate::{
     common::{crypto::hash::Hash, namespace::Namespace},
     storage::mkvs::{
         cache::{Cache, LRUCache, UpdateList},
-        tree::{Node, NodeBox, NodeKind, NodePtrRef, Root, Tree},
+        tree::{guard, AuditOp, Node, NodeBox, NodeKind, NodePtrRef, Root, SlowOp, Tree},
     },
 };
 
+/// A key updated by a commit together with the number of nodes that had to be rewritten
+/// on the path from the root to its leaf (inclusive).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HotKey {
+    pub key: Vec<u8>,
+    pub rewrites: usize,
+}
+
+/// Statistics collected during the last commit.
+///
+/// These are only collected when hot key tracking is enabled via the tree builder.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CommitStats {
+    /// Keys with the most node rewrites, ordered by decreasing number of rewrites (and
+    /// by key for equal number of rewrites).
+    pub hot_keys: Vec<HotKey>,
+}
+
 impl Tree {
     /// Commit tree updates to the underlying database and return
     /// the write log and new merkle root.
     pub fn commit(&mut self, namespace: Namespace, version: u64) -> Result<Hash> {
+        let timer = self.start_slow_op(SlowOp::Commit);
+        let result = guard::guarded(self.guard_panics, || self._commit_top(namespace, version));
+        self.finish_slow_op(timer);
+        result
+    }
+
+    fn _commit_top(&mut self, namespace: Namespace, version: u64) -> Result<Hash> {
         let mut update_list: UpdateList<LRUCache> = UpdateList::new();
         let pending_root = self.cache.borrow().get_pending_root();
-        let new_hash = _commit(pending_root, &mut update_list)?;
+        let mut hot_keys = if self.hot_key_limit > 0 {
+            Some(Vec::new())
+        } else {
+            None
+        };
+
+        // If there are no updates, the root stays the same and nothing needs to be written.
+        let clean = pending_root.borrow().clean;
+        let new_hash = if clean {
+            pending_root.borrow().hash
+        } else {
+            #[cfg(feature = "debug-invariants")]
+            let rewritten = self.collect_rewritten();
+
+            let new_hash = _commit_ex(pending_root, &mut update_list, 0, hot_keys.as_mut())?;
+            update_list.commit(&mut self.cache.borrow_mut());
+
+            #[cfg(feature = "debug-invariants")]
+            self.check_commit_invariants(rewritten);
 
-        update_list.commit(&mut self.cache.borrow_mut());
+            new_hash
+        };
 
         self.cache.borrow_mut().set_sync_root(Root {
             namespace,
@@ -25,11 +69,42 @@ impl Tree {
             hash: new_hash,
         });
 
+        if let Some(mut hot_keys) = hot_keys {
+            hot_keys.sort_by(|a: &HotKey, b: &HotKey| {
+                b.rewrites.cmp(&a.rewrites).then_with(|| a.key.cmp(&b.key))
+            });
+            hot_keys.truncate(self.hot_key_limit);
+            self.commit_stats = Some(CommitStats { hot_keys });
+        }
+
+        if let Some(audit_log) = self.audit_log.as_mut() {
+            audit_log.append(AuditOp::Commit, Hash::empty_hash(), new_hash)?;
+        }
+
         Ok(new_hash)
     }
+
+    /// Return statistics collected during the last commit.
+    ///
+    /// Returns `None` if hot key tracking is disabled or nothing has been committed yet.
+    pub fn commit_stats(&self) -> Option<&CommitStats> {
+        self.commit_stats.as_ref()
+    }
 }
 
 pub fn _commit<C: Cache>(ptr: NodePtrRef, update_list: &mut UpdateList<C>) -> Result<Hash> {
+    _commit_ex(ptr, update_list, 0, None)
+}
+
+/// Commit the subtree at the given pointer, where `depth` is the number of internal nodes
+/// above it. If `hot_keys` is given, all dirty leaves are recorded together with the number
+/// of rewritten nodes on their path.
+fn _commit_ex<C: Cache>(
+    ptr: NodePtrRef,
+    update_list: &mut UpdateList<C>,
+    depth: usize,
+    mut hot_keys: Option<&mut Vec<HotKey>>,
+) -> Result<Hash> {
     if ptr.borrow().clean {
         return Ok(ptr.borrow().hash);
     }
@@ -47,9 +122,14 @@ pub fn _commit<C: Cache>(ptr: NodePtrRef, update_list: &mut UpdateList<C>) -> Re
                 let int_left = noderef_as!(some_node_ref, Internal).left.clone();
                 let int_right = noderef_as!(some_node_ref, Internal).right.clone();
 
-                _commit(int_leaf_node, update_list)?;
-                _commit(int_left, update_list)?;
-                _commit(int_right, update_list)?;
+                _commit_ex(
+                    int_leaf_node,
+                    update_list,
+                    depth + 1,
+                    hot_keys.as_deref_mut(),
+                )?;
+                _commit_ex(int_left, update_list, depth + 1, hot_keys.as_deref_mut())?;
+                _commit_ex(int_right, update_list, depth + 1, hot_keys)?;
 
                 some_node_ref.borrow_mut().update_hash();
                 ptr.borrow_mut().hash = some_node_ref.borrow().get_hash();
@@ -67,6 +147,13 @@ pub fn _commit<C: Cache>(ptr: NodePtrRef, update_list: &mut UpdateList<C>) -> Re
                 node_ref.borrow_mut().update_hash();
                 ptr.borrow_mut().hash = node_ref.borrow().get_hash();
 
+                if let Some(hot_keys) = hot_keys {
+                    hot_keys.push(HotKey {
+                        key: noderef_as!(node_ref, Leaf).key.clone(),
+                        rewrites: depth + 1,
+                    });
+                }
+
                 update_list.push(Box::new(move |_| {
                     noderef_as_mut!(node_ref, Leaf).clean = true
                 }));
@@ -1,9 +1,24 @@
 use thiserror::Error;
 
+use crate::storage::mkvs::tree::{Depth, WriteConflict};
+
 #[derive(Error, Debug)]
 pub enum TreeError {
     #[error("mkvs: malformed node")]
     MalformedNode,
     #[error("mkvs: malformed key")]
     MalformedKey,
+    #[error("mkvs: incomplete state: {0}")]
+    IncompleteState(String),
+    #[error("mkvs: proof node budget of {budget} exceeded at bit depth {bit_depth}")]
+    ProofBudgetExceeded {
+        budget: usize,
+        cache_hits: usize,
+        fetches: usize,
+        bit_depth: Depth,
+    },
+    #[error("mkvs: internal error: {0}")]
+    Internal(String),
+    #[error("mkvs: write log conflicts with {} local changes", .0.len())]
+    WriteLogConflict(Vec<WriteConflict>),
 }
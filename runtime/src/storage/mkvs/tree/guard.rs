@@ -0,0 +1,93 @@
+use std::panic::{self, AssertUnwindSafe};
+
+use anyhow::Result;
+
+use crate::storage::mkvs::tree::TreeError;
+
+/// Run the given operation, converting any panic into `TreeError::Internal` if enabled.
+pub(super) fn guarded<R, F>(enabled: bool, f: F) -> Result<R>
+where
+    F: FnOnce() -> Result<R>,
+{
+    if !enabled {
+        return f();
+    }
+
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|payload| {
+        let msg = if let Some(msg) = payload.downcast_ref::<&str>() {
+            msg.to_string()
+        } else if let Some(msg) = payload.downcast_ref::<String>() {
+            msg.clone()
+        } else {
+            "unknown panic".to_string()
+        };
+        Err(TreeError::Internal(msg).into())
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::any::Any;
+
+    use anyhow::Result;
+
+    use crate::{
+        common::crypto::hash::Hash,
+        storage::mkvs::{
+            sync::{GetPrefixesRequest, GetRequest, IterateRequest, ProofResponse, ReadSync},
+            tree::{Root, RootType, Tree, TreeError},
+        },
+    };
+
+    /// A read syncer which panics on every request.
+    struct PanickingReadSyncer;
+
+    impl ReadSync for PanickingReadSyncer {
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+
+        fn sync_get(&mut self, _request: GetRequest) -> Result<ProofResponse> {
+            panic!("read syncer exploded");
+        }
+
+        fn sync_get_prefixes(&mut self, _request: GetPrefixesRequest) -> Result<ProofResponse> {
+            panic!("read syncer exploded");
+        }
+
+        fn sync_iterate(&mut self, _request: IterateRequest) -> Result<ProofResponse> {
+            panic!("read syncer exploded");
+        }
+    }
+
+    fn remote_tree(guard: bool) -> Tree {
+        Tree::builder()
+            .with_root(Root {
+                root_type: RootType::State,
+                hash: Hash::digest_bytes(b"remote root"),
+                ..Default::default()
+            })
+            .with_panic_guard(guard)
+            .build(Box::new(PanickingReadSyncer))
+    }
+
+    #[test]
+    fn test_panic_guard() {
+        let mut tree = remote_tree(true);
+
+        let err = tree.get(b"foo").expect_err("get should fail");
+        match err.downcast_ref::<TreeError>() {
+            Some(TreeError::Internal(msg)) => assert_eq!(msg, "read syncer exploded"),
+            _ => panic!("unexpected error: {:?}", err),
+        }
+        assert!(tree.insert(b"foo", b"bar").is_err());
+        assert!(tree.remove(b"foo").is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "read syncer exploded")]
+    fn test_panic_guard_disabled() {
+        let tree = remote_tree(false);
+        let _ = tree.get(b"foo");
+    }
+}
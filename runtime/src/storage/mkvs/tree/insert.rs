@@ -2,16 +2,36 @@ use std::mem;
 
 use anyhow::{anyhow, Result};
 
-use crate::storage::mkvs::{
-    cache::Cache,
-    tree::{Depth, Key, KeyTrait, NodeBox, NodeKind, NodePointer, NodePtrRef, Tree, Value},
+use crate::{
+    common::crypto::hash::Hash,
+    storage::mkvs::{
+        cache::Cache,
+        tree::{
+            AuditOp, Depth, Key, KeyTrait, NodeBox, NodeKind, NodePointer, NodePtrRef, SlowOp,
+            Tree, Value,
+        },
+    },
 };
 
-use super::lookup::FetcherSyncGet;
+use super::{guard, lookup::FetcherSyncGet, slow_ops::SlowOpTimer};
 
 impl Tree {
     /// Insert a key/value pair into the tree.
     pub fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>> {
+        let mut timer = self.start_slow_op(SlowOp::Insert);
+        let result = guard::guarded(self.guard_panics, || {
+            self._insert_top(key, value, timer.as_mut())
+        });
+        self.finish_slow_op(timer);
+        result
+    }
+
+    fn _insert_top(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        timer: Option<&mut SlowOpTimer>,
+    ) -> Result<Option<Vec<u8>>> {
         let pending_root = self.cache.borrow().get_pending_root();
         let boxed_key = key.to_vec();
         let boxed_val = value.to_vec();
@@ -21,10 +41,69 @@ impl Tree {
 
         let (new_root, old_val) = self._insert(pending_root, 0, &boxed_key, boxed_val)?;
         self.cache.borrow_mut().set_pending_root(new_root);
+        self.record_slow_op_depth(timer, &boxed_key);
+
+        #[cfg(feature = "debug-invariants")]
+        self.check_invariants("insert", key);
+
+        // Only record the operation once it has succeeded.
+        if let Some(audit_log) = self.audit_log.as_mut() {
+            audit_log.append(
+                AuditOp::Insert,
+                Hash::digest_bytes(key),
+                Hash::digest_bytes(value),
+            )?;
+        }
 
         Ok(old_val)
     }
 
+    /// Move the value stored at key `from` to key `to`, overwriting any existing value at
+    /// `to`. Returns whether `from` existed; if it did not, the tree is left unchanged.
+    ///
+    /// If the rename fails, the tree is left unchanged as well.
+    pub fn rename(&mut self, from: &[u8], to: &[u8]) -> Result<bool> {
+        if from == to {
+            return Ok(self.get(from)?.is_some());
+        }
+
+        let value = match self.get(from)? {
+            Some(value) => value,
+            None => return Ok(false),
+        };
+
+        // Insert at the target first so that failing to resolve the path to it does not lose
+        // the value stored at the source.
+        let previous = self.insert(to, &value)?;
+        if let Err(err) = self.remove(from) {
+            // The path to the target has just been resolved, so restoring it does not need
+            // to fetch anything.
+            match previous {
+                Some(previous) => self.insert(to, &previous)?,
+                None => self.remove(to)?,
+            };
+            return Err(err);
+        }
+        Ok(true)
+    }
+
+    /// Get an existing key, or if it does not exist, call the loader and insert the value it
+    /// returns (if any). The inserted value is not committed.
+    pub fn get_or_populate<F>(&mut self, key: &[u8], loader: F) -> Result<Option<Vec<u8>>>
+    where
+        F: FnOnce(&[u8]) -> Option<Vec<u8>>,
+    {
+        if let Some(value) = self.get(key)? {
+            return Ok(Some(value));
+        }
+
+        let value = loader(key);
+        if let Some(ref value) = value {
+            self.insert(key, value)?;
+        }
+        Ok(value)
+    }
+
     fn _insert(
         &mut self,
         ptr: NodePtrRef,
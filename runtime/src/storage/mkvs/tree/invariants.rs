@@ -0,0 +1,335 @@
+//! Structural invariant checks, enabled by the `debug-invariants` feature.
+use std::fmt::Write;
+
+use rustc_hex::ToHex;
+
+use crate::{
+    common::crypto::hash::Hash,
+    storage::mkvs::{
+        cache::Cache,
+        tree::{Depth, InternalNode, Key, KeyTrait, Node, NodeBox, NodePtrRef, NodeRef, Tree},
+    },
+};
+
+/// Nodes rewritten by a commit, with the bit depth at which they are located.
+pub(super) type RewrittenNodes = Vec<(Depth, NodePtrRef, NodeRef)>;
+
+impl Tree {
+    /// Check invariants of all locally available nodes on the path to the given key, panicking
+    /// with a diagnostic in case any of them is violated.
+    ///
+    /// This is called after every mutation with the operation that caused it.
+    pub(super) fn check_invariants(&self, op: &str, key: &[u8]) {
+        let pending_root = self.cache.borrow().get_pending_root();
+        let mut path = Vec::new();
+        if let Err(violation) = check_path(pending_root, 0, &Key::new(), &key.to_vec(), &mut path) {
+            let mut msg = format!(
+                "mkvs: invariant violated after {} of key {}: {}\npath from root:",
+                op,
+                key_digest(key),
+                violation
+            );
+            for (bit_depth, node) in path {
+                let _ = write!(msg, "\n  at bit depth {}: {}", bit_depth, node);
+            }
+            panic!("{}", msg);
+        }
+    }
+
+    /// Collect all locally available dirty nodes, which will be rewritten by the next commit.
+    pub(super) fn collect_rewritten(&self) -> RewrittenNodes {
+        let mut nodes = Vec::new();
+        collect_dirty(self.cache.borrow().get_pending_root(), 0, &mut nodes);
+        nodes
+    }
+
+    /// Check that every node rewritten by a commit is clean and that its stored hash matches
+    /// a hash recomputed from its contents, panicking with a diagnostic otherwise.
+    pub(super) fn check_commit_invariants(&self, nodes: RewrittenNodes) {
+        for (bit_depth, ptr, node_ref) in nodes {
+            let result = if node_ref.borrow().is_clean() {
+                check_node(&ptr, &node_ref)
+            } else {
+                Err("rewritten node is still dirty".to_string())
+            };
+            if let Err(violation) = result {
+                panic!(
+                    "mkvs: invariant violated after commit: {}\nrewritten node at bit depth {}: {}",
+                    violation,
+                    bit_depth,
+                    describe(&node_ref)
+                );
+            }
+        }
+    }
+}
+
+fn collect_dirty(ptr: NodePtrRef, bit_depth: Depth, nodes: &mut RewrittenNodes) {
+    let node_ref = match ptr.borrow().node {
+        Some(ref node_ref) if !ptr.borrow().clean => node_ref.clone(),
+        _ => return,
+    };
+    nodes.push((bit_depth, ptr.clone(), node_ref.clone()));
+
+    let node = node_ref.borrow();
+    if let NodeBox::Internal(ref n) = *node {
+        let bit_length = bit_depth + n.label_bit_length;
+        for child in &[&n.leaf_node, &n.left, &n.right] {
+            collect_dirty((*child).clone(), bit_length, nodes);
+        }
+    }
+}
+
+fn check_path(
+    ptr: NodePtrRef,
+    bit_depth: Depth,
+    path_key: &Key,
+    key: &Key,
+    path: &mut Vec<(Depth, String)>,
+) -> Result<(), String> {
+    let node_ref = match ptr.borrow().node {
+        Some(ref node_ref) => node_ref.clone(),
+        // Not available locally, nothing to check.
+        None => return Ok(()),
+    };
+    path.push((bit_depth, describe(&node_ref)));
+    check_node(&ptr, &node_ref)?;
+
+    let node = node_ref.borrow();
+    let n = match *node {
+        NodeBox::Internal(ref n) => n,
+        NodeBox::Leaf(ref n) => {
+            if n.key
+                .common_prefix_len(n.key.bit_length(), path_key, bit_depth)
+                != bit_depth
+            {
+                return Err("leaf key does not match the path".to_string());
+            }
+            return Ok(());
+        }
+    };
+
+    let bit_length = bit_depth + n.label_bit_length;
+    let new_path = path_key.merge(bit_depth, &n.label, n.label_bit_length);
+    let children = [&n.leaf_node, &n.left, &n.right];
+    if children.iter().filter(|child| !is_empty(child)).count() < 2 {
+        return Err("internal node with less than two children".to_string());
+    }
+
+    // Check direct children.
+    if let Some(ref child_ref) = n.leaf_node.borrow().node {
+        check_node(&n.leaf_node, child_ref)?;
+        match *child_ref.borrow() {
+            NodeBox::Leaf(ref leaf) => {
+                if leaf.key.bit_length() != bit_length
+                    || leaf
+                        .key
+                        .common_prefix_len(bit_length, &new_path, bit_length)
+                        != bit_length
+                {
+                    return Err("leaf node key does not match the path".to_string());
+                }
+            }
+            NodeBox::Internal(_) => return Err("leaf node is an internal node".to_string()),
+        }
+    }
+    for (child, bit) in [(&n.left, false), (&n.right, true)] {
+        if let Some(ref child_ref) = child.borrow().node {
+            check_node(child, child_ref)?;
+            let valid = match *child_ref.borrow() {
+                NodeBox::Internal(ref c) => c.label_bit_length > 0 && c.label.get_bit(0) == bit,
+                NodeBox::Leaf(ref c) => {
+                    c.key.bit_length() > bit_length && c.key.get_bit(bit_length) == bit
+                }
+            };
+            if !valid {
+                return Err(format!("child on the wrong side (expected bit {})", bit));
+            }
+        }
+    }
+
+    // Continue along the path to the key.
+    if key.bit_length() < bit_length {
+        Ok(())
+    } else if key.bit_length() == bit_length {
+        check_path(n.leaf_node.clone(), bit_length, &new_path, key, path)
+    } else if key.get_bit(bit_length) {
+        check_path(n.right.clone(), bit_length, &new_path, key, path)
+    } else {
+        check_path(n.left.clone(), bit_length, &new_path, key, path)
+    }
+}
+
+/// Check that clean nodes are consistent with their pointers and have correct hashes.
+fn check_node(ptr: &NodePtrRef, node_ref: &NodeRef) -> Result<(), String> {
+    let ptr = ptr.borrow();
+    let node = node_ref.borrow();
+    if ptr.clean && !node.is_clean() {
+        return Err("clean pointer to a dirty node".to_string());
+    }
+    if !node.is_clean() {
+        return Ok(());
+    }
+    if ptr.clean && ptr.hash != node.get_hash() {
+        return Err("pointer hash does not match node hash".to_string());
+    }
+
+    let expected = match *node {
+        NodeBox::Internal(ref n) => {
+            for child in &[&n.leaf_node, &n.left, &n.right] {
+                if !child.borrow().clean {
+                    return Err("clean node with a dirty child".to_string());
+                }
+            }
+
+            let mut copy = InternalNode {
+                clean: true,
+                label: n.label.clone(),
+                label_bit_length: n.label_bit_length,
+                leaf_node: n.leaf_node.clone(),
+                left: n.left.clone(),
+                right: n.right.clone(),
+                ..Default::default()
+            };
+            copy.update_hash();
+            copy.get_hash()
+        }
+        NodeBox::Leaf(ref n) => {
+            let mut copy = n.copy();
+            copy.update_hash();
+            copy.get_hash()
+        }
+    };
+    if expected != node.get_hash() {
+        return Err(format!(
+            "stored hash {:?} does not match computed hash {:?}",
+            node.get_hash(),
+            expected
+        ));
+    }
+
+    Ok(())
+}
+
+fn is_empty(ptr: &NodePtrRef) -> bool {
+    let ptr = ptr.borrow();
+    ptr.node.is_none() && ptr.is_null()
+}
+
+fn describe(node_ref: &NodeRef) -> String {
+    match *node_ref.borrow() {
+        NodeBox::Internal(ref n) => format!(
+            "internal (label {}/{} bits, clean {})",
+            key_digest(&n.label),
+            n.label_bit_length,
+            n.clean
+        ),
+        NodeBox::Leaf(ref n) => format!("leaf (key {}, clean {})", key_digest(&n.key), n.clean),
+    }
+}
+
+/// A short digest identifying a key, so diagnostics do not leak key contents.
+fn key_digest(key: &[u8]) -> String {
+    format!(
+        "#{}",
+        Hash::digest_bytes(key).truncated(8).to_hex::<String>()
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use std::panic::{self, AssertUnwindSafe};
+
+    use super::key_digest;
+    use crate::storage::mkvs::{
+        cache::Cache,
+        sync::NoopReadSyncer,
+        tree::{NodeBox, NodePointer, RootType, Tree},
+    };
+
+    fn generate_tree() -> Tree {
+        let mut tree = Tree::builder()
+            .with_root_type(RootType::State)
+            .build(Box::new(NoopReadSyncer));
+        tree.insert(b"foo", b"bar").expect("insert");
+        tree.insert(b"moo", b"boo").expect("insert");
+        tree.commit(Default::default(), 0).expect("commit");
+        tree
+    }
+
+    #[test]
+    fn test_invariants() {
+        let mut tree = generate_tree();
+        for i in 0..100 {
+            tree.insert(format!("key {}", i).as_bytes(), b"value")
+                .expect("insert");
+        }
+        for i in 0..100 {
+            tree.remove(format!("key {}", i).as_bytes())
+                .expect("remove");
+        }
+        tree.commit(Default::default(), 1).expect("commit");
+        for i in 0..100 {
+            tree.remove(format!("key {}", i).as_bytes())
+                .expect("remove");
+        }
+        tree.remove(b"missing").expect("remove");
+        tree.commit(Default::default(), 2).expect("commit");
+    }
+
+    #[test]
+    #[should_panic(expected = "internal node with less than two children")]
+    fn test_invariants_single_child() {
+        let mut tree = generate_tree();
+
+        // Remove a child without collapsing the node.
+        let root = tree.cache.borrow().get_pending_root();
+        if let NodeBox::Internal(ref mut n) = *root.borrow().get_node().borrow_mut() {
+            n.right = NodePointer::null_ptr();
+        }
+
+        tree.insert(b"foo", b"baz").expect("insert");
+    }
+
+    #[test]
+    #[should_panic(expected = "does not match computed hash")]
+    fn test_invariants_stale_hash() {
+        let mut tree = generate_tree();
+
+        // Modify a leaf without marking it dirty.
+        let root = tree.cache.borrow().get_pending_root();
+        if let NodeBox::Internal(ref n) = *root.borrow().get_node().borrow() {
+            if let NodeBox::Leaf(ref mut leaf) = *n.left.borrow().get_node().borrow_mut() {
+                leaf.value = b"changed".to_vec();
+            }
+        }
+
+        tree.insert(b"moo", b"baz").expect("insert");
+    }
+
+    #[test]
+    fn test_invariants_commit_stale_hash() {
+        let mut tree = generate_tree();
+        tree.insert(b"foo", b"baz").expect("insert");
+
+        let rewritten = tree.collect_rewritten();
+        assert!(!rewritten.is_empty());
+        tree.commit(Default::default(), 1).expect("commit");
+
+        // Modify a rewritten leaf after its hash has been computed.
+        for (_, _, node_ref) in &rewritten {
+            if let NodeBox::Leaf(ref mut leaf) = *node_ref.borrow_mut() {
+                leaf.value = b"changed".to_vec();
+            }
+        }
+
+        let err = panic::catch_unwind(AssertUnwindSafe(|| tree.check_commit_invariants(rewritten)))
+            .expect_err("check should panic");
+        let msg = err.downcast_ref::<String>().expect("panic message");
+        assert!(msg.contains("invariant violated after commit"));
+        assert!(msg.contains("does not match computed hash"));
+        // Keys are only identified by their digest.
+        assert!(msg.contains(&key_digest(b"foo")));
+        assert!(!msg.contains(&format!("{:?}", b"foo")));
+    }
+}
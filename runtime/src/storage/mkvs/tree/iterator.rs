@@ -1,13 +1,16 @@
 //! Tree iterator.
 use std::{collections::VecDeque, fmt};
 
-use anyhow::{Error, Result};
-
-use crate::storage::mkvs::{
-    self,
-    cache::{Cache, ReadSyncFetcher},
-    sync::{IterateRequest, Proof, ReadSync, TreeID},
-    tree::{Depth, Key, KeyTrait, NodeBox, NodeKind, NodePtrRef, Root, Tree},
+use anyhow::{anyhow, Error, Result};
+
+use crate::{
+    common::crypto::hash::Hash,
+    storage::mkvs::{
+        self,
+        cache::{Cache, ReadSyncFetcher},
+        sync::{IterateRequest, Proof, ReadSync, TreeID},
+        tree::{Depth, Key, KeyTrait, NodeBox, NodeKind, NodePtrRef, Root, Tree},
+    },
 };
 
 pub(super) struct FetcherSyncIterate<'a> {
@@ -64,6 +67,8 @@ impl fmt::Debug for PathAtom {
 }
 
 /// Tree iterator.
+///
+/// Entries are yielded in lexicographic key order, see `mkvs::Iterator`.
 pub struct TreeIterator<'tree> {
     tree: &'tree Tree,
     prefetch: usize,
@@ -71,6 +76,8 @@ pub struct TreeIterator<'tree> {
     key: Option<Key>,
     value: Option<Vec<u8>>,
     error: Option<Error>,
+    /// Number of nodes dereferenced so far.
+    visited_nodes: usize,
 }
 
 impl<'tree> TreeIterator<'tree> {
@@ -83,6 +90,7 @@ impl<'tree> TreeIterator<'tree> {
             key: None,
             value: None,
             error: None,
+            visited_nodes: 0,
         }
     }
 
@@ -144,6 +152,7 @@ impl<'tree> TreeIterator<'tree> {
         mut key: Key,
         mut state: VisitState,
     ) -> Result<()> {
+        self.visited_nodes += 1;
         let node_ref = self.tree.cache.borrow_mut().deref_node_ptr(
             ptr.clone(),
             Some(FetcherSyncIterate::new(&key, self.prefetch)),
@@ -343,12 +352,99 @@ impl Tree {
     pub fn iter(&self) -> TreeIterator {
         TreeIterator::new(self)
     }
+
+    /// Iterate starting at the given cursor (or at the first key if no cursor is given),
+    /// calling `f` for each entry in key order, and yield once `budget` nodes have been
+    /// dereferenced.
+    ///
+    /// The budget is checked after each entry, so a call dereferences at most `budget` nodes
+    /// plus the nodes needed to reach one more entry, which is bounded by the depth of the
+    /// tree. Each call visits at least one entry so that iteration always makes progress.
+    ///
+    /// Returns a cursor which can be used to resume iteration, or `None` if there are no more
+    /// entries. Cursors are only valid for the committed root they were created at, so the
+    /// tree must not have any uncommitted updates.
+    pub fn iterate_budgeted<F>(
+        &self,
+        cursor: Option<IterationCursor>,
+        budget: usize,
+        f: F,
+    ) -> Result<Option<IterationCursor>>
+    where
+        F: FnMut(&[u8], &[u8]),
+    {
+        self._iterate_budgeted(cursor, budget, f)
+            .map(|(cursor, _)| cursor)
+    }
+
+    /// Budgeted iteration, also returning the number of dereferenced nodes.
+    fn _iterate_budgeted<F>(
+        &self,
+        cursor: Option<IterationCursor>,
+        budget: usize,
+        mut f: F,
+    ) -> Result<(Option<IterationCursor>, usize)>
+    where
+        F: FnMut(&[u8], &[u8]),
+    {
+        use mkvs::Iterator;
+
+        if budget == 0 {
+            return Err(anyhow!("mkvs: iteration budget must be non-zero"));
+        }
+        let pending_root = self.cache.borrow().get_pending_root();
+        if !pending_root.borrow().clean {
+            return Err(anyhow!(
+                "mkvs: budgeted iteration requires a committed tree"
+            ));
+        }
+        let root = pending_root.borrow().hash;
+
+        let mut it = self.iter();
+        match cursor {
+            Some(cursor) if cursor.root != root => {
+                return Err(anyhow!("mkvs: iteration cursor is for a different root"));
+            }
+            Some(cursor) => it.seek(&cursor.next_key),
+            None => it.rewind(),
+        }
+
+        while it.is_valid() {
+            f(
+                it.get_key().as_ref().expect("iterator is valid"),
+                it.get_value().as_ref().expect("iterator is valid"),
+            );
+            Iterator::next(&mut it);
+            if it.visited_nodes >= budget {
+                break;
+            }
+        }
+        if let Some(error) = it.error() {
+            return Err(anyhow!("mkvs: failed to iterate tree: {}", error));
+        }
+
+        let cursor = it.get_key().as_ref().map(|next_key| IterationCursor {
+            root,
+            next_key: next_key.clone(),
+        });
+        Ok((cursor, it.visited_nodes))
+    }
+}
+
+/// A cursor for resuming budgeted iteration.
+///
+/// It consists of the root the iteration was started at and the next key to visit.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IterationCursor {
+    root: Hash,
+    next_key: Key,
 }
 
 #[cfg(test)]
 pub(super) mod test {
-    use std::iter;
+    use std::{collections::BTreeMap, iter};
 
+    use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
     use rustc_hex::FromHex;
 
     use super::{super::tree_test::generate_key_value_pairs_ex, *};
@@ -547,6 +643,146 @@ pub(super) mod test {
         );
     }
 
+    #[test]
+    fn test_iterator_deterministic_order() {
+        // Logical contents, including an empty key and keys that are prefixes of others.
+        let (keys, values) = generate_key_value_pairs_ex("".to_string(), 200);
+        let mut expected: BTreeMap<Vec<u8>, Vec<u8>> = keys.into_iter().zip(values).collect();
+        expected.insert(b"".to_vec(), b"empty".to_vec());
+        expected.insert(vec![0x00], b"zero".to_vec());
+        expected.insert(vec![0x00, 0x00], b"zero zero".to_vec());
+        expected.insert(vec![0xff; 33], b"max".to_vec());
+        let expected: Vec<(Vec<u8>, Vec<u8>)> = expected.into_iter().collect();
+
+        let collect = |mut it: Box<dyn Iterator + '_>| {
+            it.rewind();
+            let items: Vec<(Vec<u8>, Vec<u8>)> = it.by_ref().collect();
+            assert!(it.error().is_none(), "iterator should not error");
+            items
+        };
+
+        // Tree rebuilt from the logical contents.
+        let mut fresh = Tree::builder()
+            .with_root_type(RootType::State)
+            .build(Box::new(NoopReadSyncer));
+        for (key, value) in &expected {
+            fresh.insert(key, value).unwrap();
+        }
+        assert_eq!(collect(Box::new(fresh.iter())), expected);
+
+        for seed in 0..10 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut items = expected.clone();
+            items.shuffle(&mut rng);
+
+            // Insert in random order, interleaved with overwrites and removals of junk keys
+            // and commits at random points.
+            let mut tree = Tree::builder()
+                .with_root_type(RootType::State)
+                .build(Box::new(NoopReadSyncer));
+            for (i, (key, value)) in items.iter().enumerate() {
+                if rng.gen_bool(0.2) {
+                    tree.insert(key, b"stale value").unwrap();
+                }
+                tree.insert(key, value).unwrap();
+
+                let junk = format!("junk {}", i).into_bytes();
+                tree.insert(&junk, b"junk").unwrap();
+                if rng.gen_bool(0.1) {
+                    tree.commit(Default::default(), i as u64).unwrap();
+                }
+                tree.remove(&junk).unwrap();
+            }
+            assert_eq!(
+                collect(Box::new(tree.iter())),
+                expected,
+                "iteration order should not depend on history (seed {})",
+                seed
+            );
+
+            // Same contents split between a committed tree and an overlay.
+            let split = rng.gen_range(0..items.len());
+            let mut tree = Tree::builder()
+                .with_root_type(RootType::State)
+                .build(Box::new(NoopReadSyncer));
+            for (key, value) in &items[..split] {
+                tree.insert(key, value).unwrap();
+            }
+            tree.insert(b"junk", b"junk").unwrap();
+            tree.commit(Default::default(), 0).unwrap();
+
+            let mut overlay = OverlayTree::new(&mut tree);
+            for (key, value) in &items[split..] {
+                overlay.insert(key, value).unwrap();
+            }
+            overlay.remove(b"junk").unwrap();
+            assert_eq!(
+                collect(Box::new(overlay.iter())),
+                expected,
+                "overlay iteration order should not depend on history (seed {})",
+                seed
+            );
+        }
+    }
+
+    #[test]
+    fn test_iterate_budgeted() {
+        let mut tree = Tree::builder()
+            .with_root_type(RootType::State)
+            .build(Box::new(NoopReadSyncer));
+
+        let (keys, values) = generate_key_value_pairs_ex("".to_string(), 1000);
+        for (key, value) in keys.iter().zip(values.iter()) {
+            tree.insert(key, value).unwrap();
+        }
+
+        // Iteration requires a committed tree.
+        assert!(tree.iterate_budgeted(None, 10, |_, _| {}).is_err());
+        tree.commit(Default::default(), 0).unwrap();
+        assert!(tree.iterate_budgeted(None, 0, |_, _| {}).is_err());
+
+        // The budget is charged per dereferenced node, so reaching the next entry may only
+        // overshoot it by the nodes on a single path.
+        const BUDGET: usize = 7;
+        const MAX_OVERSHOOT: usize = 64;
+
+        let mut visited = Vec::new();
+        let mut cursor = None;
+        let mut calls = 0;
+        loop {
+            let mut count = 0;
+            let (next_cursor, nodes) = tree
+                ._iterate_budgeted(cursor, BUDGET, |key, value| {
+                    visited.push((key.to_vec(), value.to_vec()));
+                    count += 1;
+                })
+                .unwrap();
+            cursor = next_cursor;
+            calls += 1;
+            assert!(count >= 1, "each call should make progress");
+            assert!(count < BUDGET, "each entry should cost more than one node");
+            assert!(
+                nodes <= BUDGET + MAX_OVERSHOOT,
+                "node budget should be respected (visited {} nodes)",
+                nodes
+            );
+            if cursor.is_none() {
+                break;
+            }
+        }
+        assert!(calls > keys.len().div_ceil(BUDGET));
+
+        let mut expected: Vec<(Vec<u8>, Vec<u8>)> = keys.into_iter().zip(values).collect();
+        expected.sort();
+        assert_eq!(visited, expected, "all keys should be visited exactly once");
+
+        // Cursors are invalidated by root changes.
+        let cursor = tree.iterate_budgeted(None, 1, |_, _| {}).unwrap();
+        tree.insert(b"another key", b"value").unwrap();
+        tree.commit(Default::default(), 1).unwrap();
+        assert!(tree.iterate_budgeted(cursor, 1, |_, _| {}).is_err());
+    }
+
     #[test]
     fn test_iterator_eviction() {
         let server = ProtocolServer::new(None);
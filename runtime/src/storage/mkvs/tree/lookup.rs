@@ -1,9 +1,17 @@
+use std::collections::HashMap;
+
 use anyhow::Result;
 
-use crate::storage::mkvs::{
-    cache::{Cache, ReadSyncFetcher},
-    sync::{GetRequest, Proof, ProofBuilder, ReadSync, TreeID},
-    tree::{Depth, Key, KeyTrait, NodeBox, NodeKind, NodePtrRef, Root, Tree, Value},
+use crate::{
+    common::crypto::hash::Hash,
+    storage::mkvs::{
+        cache::{Cache, ReadSyncFetcher},
+        sync::{GetRequest, Proof, ProofBuilder, ReadSync, TreeID},
+        tree::{
+            guard, slow_ops::SlowOpTimer, Depth, Key, KeyTrait, Node, NodeBox, NodeKind,
+            NodePtrRef, NodeRef, Root, SlowOp, Tree, TreeError, Value,
+        },
+    },
 };
 
 pub(super) struct FetcherSyncGet<'a> {
@@ -34,45 +42,307 @@ impl<'a> ReadSyncFetcher for FetcherSyncGet<'a> {
     }
 }
 
+/// Observation of the work done by a single lookup, see `Tree::get_observed`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReadObservation {
+    /// Number of nodes traversed, including the leaf node.
+    pub depth: usize,
+    /// Number of proofs fetched via the read syncer.
+    pub fetches: usize,
+    /// Total size of proof entries fetched via the read syncer.
+    pub fetched_bytes: usize,
+    /// Number of nodes dereferenced from the cache.
+    pub cache_hits: usize,
+    /// Whether the value was found in a leaf that has not been committed yet.
+    pub uncommitted: bool,
+}
+
+/// Proof builder which limits the number of nodes visited while generating the proof.
+struct BudgetedProofBuilder {
+    builder: ProofBuilder,
+    budget: usize,
+    cache_hits: usize,
+    fetches: usize,
+}
+
+impl BudgetedProofBuilder {
+    /// Account for dereferencing the given pointer, failing if this would exceed the budget.
+    fn account(&mut self, ptr: &NodePtrRef, bit_depth: Depth) -> Result<()> {
+        let ptr = ptr.borrow();
+        let local = ptr.node.is_some();
+        if !local && ptr.is_null() {
+            return Ok(());
+        }
+
+        if self.budget > 0 && self.cache_hits + self.fetches >= self.budget {
+            return Err(TreeError::ProofBudgetExceeded {
+                budget: self.budget,
+                cache_hits: self.cache_hits,
+                fetches: self.fetches,
+                bit_depth,
+            }
+            .into());
+        }
+
+        if local {
+            self.cache_hits += 1;
+        } else {
+            self.fetches += 1;
+        }
+        Ok(())
+    }
+}
+
 impl Tree {
     /// Get an existing key.
     pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
-        self._get_top(key, false)
+        let mut timer = self.start_slow_op(SlowOp::Get);
+        let result = guard::guarded(self.guard_panics, || {
+            self._get_top(key, false, timer.as_mut())
+        });
+        self.finish_slow_op(timer);
+        result
+    }
+
+    /// Get the values of multiple keys with a single merged traversal.
+    ///
+    /// Nodes on paths shared by multiple keys are only dereferenced (and fetched) once. The
+    /// values are returned in the order of the given keys.
+    pub fn get_many(&self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>> {
+        guard::guarded(self.guard_panics, || self._get_many_top(keys))
+    }
+
+    /// Get the values of multiple keys as a map.
+    ///
+    /// Only keys present in the tree are included in the returned map. All keys are looked up
+    /// in a single traversal, so nodes on shared paths are only dereferenced once.
+    pub fn get_map(&self, keys: &[&[u8]]) -> Result<HashMap<Vec<u8>, Vec<u8>>> {
+        let values = self.get_many(keys)?;
+        Ok(keys
+            .iter()
+            .zip(values)
+            .filter_map(|(key, value)| Some((key.to_vec(), value?)))
+            .collect())
+    }
+
+    /// Get an existing key together with the hashes of all nodes traversed while looking it
+    /// up, ordered from the root towards the key.
+    ///
+    /// The hashes are also returned when the key does not exist, in which case they describe
+    /// the path up to where the lookup ended. They are only meaningful for nodes which have
+    /// been committed.
+    pub fn get_with_path_hashes(&self, key: &[u8]) -> Result<(Option<Vec<u8>>, Vec<Hash>)> {
+        let boxed_key = key.to_vec();
+        let pending_root = self.cache.borrow().get_pending_root();
+
+        // Remember where the path from root to target node ends (will end).
+        self.cache.borrow_mut().mark_position();
+
+        let mut path = Vec::new();
+        let value = self._get(pending_root, 0, &boxed_key, false, None, Some(&mut path))?;
+        let hashes = path.iter().map(|node| node.borrow().get_hash()).collect();
+        Ok((value, hashes))
     }
 
+    /// Get an existing key together with an observation of the work done by the lookup.
+    pub fn get_observed(&self, key: &[u8]) -> Result<(Option<Vec<u8>>, ReadObservation)> {
+        let boxed_key = key.to_vec();
+        let pending_root = self.cache.borrow().get_pending_root();
+        let before = self.cache.borrow().fetch_counters();
+
+        // Remember where the path from root to target node ends (will end).
+        self.cache.borrow_mut().mark_position();
+
+        let mut path = Vec::new();
+        let value = self._get(pending_root, 0, &boxed_key, false, None, Some(&mut path))?;
+
+        let after = self.cache.borrow().fetch_counters();
+        let uncommitted = value.is_some()
+            && path
+                .last()
+                .map(|node| !node.borrow().is_clean())
+                .unwrap_or(false);
+        let observation = ReadObservation {
+            depth: path.len(),
+            fetches: after.fetches - before.fetches,
+            fetched_bytes: after.fetched_bytes - before.fetched_bytes,
+            cache_hits: after.cache_hits - before.cache_hits,
+            uncommitted,
+        };
+        Ok((value, observation))
+    }
+
+    /// Get a proof for an existing key.
+    ///
+    /// Fails with `TreeError::ProofBudgetExceeded` in case generating the proof requires
+    /// more nodes than allowed by the configured proof node budget.
     pub fn get_proof(&self, key: &[u8]) -> Result<Option<Proof>> {
+        Ok(self.get_with_proof(key)?.map(|(_, proof)| proof))
+    }
+
+    /// Get an existing key together with its proof.
+    ///
+    /// Both are collected in a single traversal, so this is cheaper than calling `get` and
+    /// `get_proof` separately on a cold cache. Fails with `TreeError::ProofBudgetExceeded`
+    /// in the same cases as `get_proof`.
+    pub fn get_with_proof(&self, key: &[u8]) -> Result<Option<(Vec<u8>, Proof)>> {
         let boxed_key = key.to_vec();
         let pending_root = self.cache.borrow().get_pending_root();
 
         // Remember where the path from root to target node ends (will end).
         self.cache.borrow_mut().mark_position();
 
-        let mut proof_builder = ProofBuilder::new(pending_root.as_ref().borrow().hash);
+        let mut proof_builder = BudgetedProofBuilder {
+            builder: ProofBuilder::new(pending_root.as_ref().borrow().hash),
+            budget: self.proof_node_budget,
+            cache_hits: 0,
+            fetches: 0,
+        };
+
+        let result = self._get(
+            pending_root,
+            0,
+            &boxed_key,
+            false,
+            Some(&mut proof_builder),
+            None,
+        )?;
+        Ok(result.map(|value| (value, proof_builder.builder.build())))
+    }
+
+    /// Get a combined proof for two keys against the same root, sharing any common part of
+    /// their paths. The proof also covers keys that do not exist, proving their absence.
+    ///
+    /// Use `verify_pair_proof` to verify the proof and obtain both values.
+    pub fn prove_pair(&self, key_a: &[u8], key_b: &[u8]) -> Result<Proof> {
+        let pending_root = self.cache.borrow().get_pending_root();
+
+        let mut proof_builder = BudgetedProofBuilder {
+            builder: ProofBuilder::new(pending_root.as_ref().borrow().hash),
+            budget: self.proof_node_budget,
+            cache_hits: 0,
+            fetches: 0,
+        };
+        for key in &[key_a, key_b] {
+            // Remember where the path from root to target node ends (will end).
+            self.cache.borrow_mut().mark_position();
 
-        let result = self._get(pending_root, 0, &boxed_key, false, Some(&mut proof_builder))?;
-        match result {
-            Some(_) => Ok(Some(proof_builder.build())),
-            None => Ok(None),
+            self._get(
+                pending_root.clone(),
+                0,
+                &key.to_vec(),
+                false,
+                Some(&mut proof_builder),
+                None,
+            )?;
         }
+        Ok(proof_builder.builder.build())
     }
 
     /// Check if the key exists in the local cache.
     pub fn cache_contains_key(&self, key: &[u8]) -> bool {
-        match self._get_top(key, true) {
+        match self._get_top(key, true, None) {
             Ok(Some(_)) => true,
             Ok(None) => false,
             Err(_) => false,
         }
     }
 
-    fn _get_top(&self, key: &[u8], check_only: bool) -> Result<Option<Vec<u8>>> {
+    fn _get_top(
+        &self,
+        key: &[u8],
+        check_only: bool,
+        timer: Option<&mut SlowOpTimer>,
+    ) -> Result<Option<Vec<u8>>> {
         let boxed_key = key.to_vec();
         let pending_root = self.cache.borrow().get_pending_root();
 
         // Remember where the path from root to target node ends (will end).
         self.cache.borrow_mut().mark_position();
 
-        self._get(pending_root, 0, &boxed_key, check_only, None)
+        let value = self._get(pending_root, 0, &boxed_key, check_only, None, None)?;
+        self.record_slow_op_depth(timer, &boxed_key);
+        Ok(value)
+    }
+
+    fn _get_many_top(&self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>> {
+        let boxed_keys: Vec<Key> = keys.iter().map(|key| key.to_vec()).collect();
+        let mut indices: Vec<usize> = (0..keys.len()).collect();
+        // Sorting keeps keys sharing a path next to each other, so each fetch serves the
+        // first key of a subtree.
+        indices.sort_by(|&a, &b| boxed_keys[a].cmp(&boxed_keys[b]));
+        let pending_root = self.cache.borrow().get_pending_root();
+
+        // Remember where the path from root to target node ends (will end).
+        self.cache.borrow_mut().mark_position();
+
+        let mut values = vec![None; keys.len()];
+        self._get_many(pending_root, 0, &boxed_keys, indices, &mut values)?;
+        Ok(values)
+    }
+
+    fn _get_many(
+        &self,
+        ptr: NodePtrRef,
+        bit_depth: Depth,
+        keys: &[Key],
+        indices: Vec<usize>,
+        values: &mut [Option<Value>],
+    ) -> Result<()> {
+        if indices.is_empty() {
+            return Ok(());
+        }
+
+        let node_ref = self
+            .cache
+            .borrow_mut()
+            .deref_node_ptr(ptr, Some(FetcherSyncGet::new(&keys[indices[0]], false)))?;
+
+        match classify_noderef!(?node_ref) {
+            NodeKind::None => Ok(()),
+            NodeKind::Internal => {
+                let (leaf_node, left, right, bit_length) = match *node_ref.unwrap().borrow() {
+                    NodeBox::Internal(ref n) => (
+                        n.leaf_node.clone(),
+                        n.left.clone(),
+                        n.right.clone(),
+                        bit_depth + n.label_bit_length,
+                    ),
+                    _ => unreachable!("node kind is internal node"),
+                };
+
+                // Split the keys the same way as a single lookup would, dropping keys which
+                // are too short for the label.
+                let mut leaf_indices = Vec::new();
+                let mut left_indices = Vec::new();
+                let mut right_indices = Vec::new();
+                for i in indices {
+                    let key = &keys[i];
+                    if key.bit_length() == bit_length {
+                        leaf_indices.push(i);
+                    } else if key.bit_length() > bit_length {
+                        if key.get_bit(bit_length) {
+                            right_indices.push(i);
+                        } else {
+                            left_indices.push(i);
+                        }
+                    }
+                }
+
+                self._get_many(leaf_node, bit_length, keys, leaf_indices, values)?;
+                self._get_many(left, bit_length, keys, left_indices, values)?;
+                self._get_many(right, bit_length, keys, right_indices, values)
+            }
+            NodeKind::Leaf => {
+                let node_ref = node_ref.unwrap();
+                for i in indices {
+                    if noderef_as!(node_ref, Leaf).key == keys[i] {
+                        values[i] = Some(noderef_as!(node_ref, Leaf).value.clone());
+                    }
+                }
+                Ok(())
+            }
+        }
     }
 
     fn _get(
@@ -81,8 +351,13 @@ impl Tree {
         bit_depth: Depth,
         key: &Key,
         check_only: bool,
-        mut proof_builder: Option<&mut ProofBuilder>,
+        mut proof_builder: Option<&mut BudgetedProofBuilder>,
+        mut path: Option<&mut Vec<NodeRef>>,
     ) -> Result<Option<Value>> {
+        if let Some(pb) = proof_builder.as_mut() {
+            pb.account(&ptr, bit_depth)?;
+        }
+
         let node_ref = self.cache.borrow_mut().deref_node_ptr(
             ptr,
             if check_only {
@@ -94,7 +369,11 @@ impl Tree {
 
         // Include nodes in proof if we have a proof builder.
         if let (Some(pb), Some(node_ref)) = (proof_builder.as_mut(), &node_ref) {
-            pb.include(&node_ref.borrow());
+            pb.builder.include(&node_ref.borrow());
+        }
+        // Record traversed nodes if requested.
+        if let (Some(path), Some(node_ref)) = (path.as_mut(), &node_ref) {
+            path.push(node_ref.clone());
         }
 
         match classify_noderef!(?node_ref) {
@@ -114,6 +393,7 @@ impl Tree {
                             key,
                             check_only,
                             proof_builder,
+                            path,
                         );
                     }
 
@@ -130,6 +410,7 @@ impl Tree {
                             key,
                             check_only,
                             proof_builder,
+                            path,
                         );
                     } else {
                         return self._get(
@@ -138,6 +419,7 @@ impl Tree {
                             key,
                             check_only,
                             proof_builder,
+                            path,
                         );
                     }
                 }
@@ -152,12 +152,11 @@ impl Marshal for InternalNode {
         pos += 1;
 
         pos += self.label_bit_length.unmarshal_binary(&data[pos..])?;
-        self.label = vec![0; self.label_bit_length.to_bytes()];
+        // Make sure the label is present before allocating it.
         if pos + self.label_bit_length.to_bytes() > data.len() {
             return Err(TreeError::MalformedNode.into());
         }
-        self.label
-            .clone_from_slice(&data[pos..pos + self.label_bit_length.to_bytes()]);
+        self.label = data[pos..pos + self.label_bit_length.to_bytes()].to_vec();
         pos += self.label_bit_length.to_bytes();
         if pos >= data.len() {
             return Err(TreeError::MalformedNode.into());
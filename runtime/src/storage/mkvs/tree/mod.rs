@@ -1,9 +1,14 @@
 #[macro_use]
 mod macros;
 
+mod audit;
+mod clock;
 mod commit;
 mod errors;
+mod guard;
 mod insert;
+#[cfg(feature = "debug-invariants")]
+mod invariants;
 mod iterator;
 mod lookup;
 mod marshal;
@@ -11,14 +16,29 @@ mod node;
 mod overlay;
 mod prefetch;
 mod remove;
+mod salvage;
+mod slow_ops;
+mod stats;
+mod verify;
+mod walk;
 
+pub use audit::{verify_audit_log, AuditError, AuditLog, AuditOp, AuditRecord, AuditSummary};
+pub use clock::{LogicalClock, MonotonicClock, TimeSource};
+pub use commit::{CommitStats, HotKey};
 pub use errors::*;
+pub use iterator::IterationCursor;
+pub use lookup::ReadObservation;
 pub use node::*;
 pub use overlay::*;
+pub use salvage::{MissingSubtree, SalvageReport};
+pub use slow_ops::{SlowOp, SlowOpCallback, SlowOpRecord, SlowOpThresholds};
+pub use stats::{storage_delta, BalanceReport, NibblePrefix, StorageDelta};
+pub use verify::{stateless_apply, verify_pair_proof, verify_write_log, Witness};
 
-use std::{cell::RefCell, fmt, rc::Rc};
+use std::{cell::RefCell, fmt, io::Write, rc::Rc};
 
 use anyhow::Result;
+use zeroize::Zeroizing;
 
 use crate::{
     common::{crypto::hash::Hash, namespace::Namespace},
@@ -31,6 +51,9 @@ pub struct Options {
     value_capacity: usize,
     root: Option<Root>,
     root_type: Option<RootType>,
+    hot_key_limit: usize,
+    proof_node_budget: usize,
+    guard_panics: bool,
 }
 
 impl Default for Options {
@@ -40,6 +63,9 @@ impl Default for Options {
             value_capacity: 16 * 1024 * 1024,
             root: None,
             root_type: None,
+            hot_key_limit: 0,
+            proof_node_budget: 65_536,
+            guard_panics: false,
         }
     }
 }
@@ -50,6 +76,9 @@ impl Default for Options {
 #[derive(Default)]
 pub struct Builder {
     options: Options,
+    audit_log: Option<AuditLog>,
+    slow_op_logger: Option<slow_ops::SlowOpLogger>,
+    time_source: Option<Box<dyn TimeSource>>,
 }
 
 impl Builder {
@@ -93,6 +122,83 @@ impl Builder {
         self
     }
 
+    /// Set the maximum number of nodes visited while generating a single proof.
+    ///
+    /// Both nodes found in the cache and nodes fetched via the read syncer count towards
+    /// the budget. If set to 0, the budget is unlimited. If left unspecified, the budget
+    /// defaults to 65_536 nodes.
+    pub fn with_proof_node_budget(mut self, budget: usize) -> Self {
+        self.options.proof_node_budget = budget;
+        self
+    }
+
+    /// Enable tracking of the keys causing the most node rewrites on commit.
+    ///
+    /// After each commit, up to `limit` keys with the deepest updated paths are reported
+    /// via `Tree::commit_stats`. If set to 0 (the default), tracking is disabled.
+    pub fn with_hot_key_tracking(mut self, limit: usize) -> Self {
+        self.options.hot_key_limit = limit;
+        self
+    }
+
+    /// Convert panics in `get`, `get_many`, `insert`, `remove` and `commit` into
+    /// `TreeError::Internal`.
+    ///
+    /// This is a safety net which prevents a single malformed input from crashing the calling
+    /// thread, not a substitute for proper error handling. After such an error, the tree may
+    /// be in an inconsistent state and should be discarded. It has no effect when panics
+    /// abort. Disabled by default.
+    pub fn with_panic_guard(mut self, enabled: bool) -> Self {
+        self.options.guard_panics = enabled;
+        self
+    }
+
+    /// Install an audit log which records all mutating operations on the tree.
+    ///
+    /// Records are HMAC-chained using the given audit key and are written to the sink once
+    /// the corresponding operation has succeeded, before it returns. Failed operations are
+    /// not recorded. Use `verify_audit_log` to check the integrity of the resulting log.
+    ///
+    /// If writing a record fails, the operation fails with `AuditError::WriteFailed` but its
+    /// changes have already been applied to the tree. Such an operation must not be retried,
+    /// and the tree no longer matches the log, so it should be discarded.
+    pub fn with_audit_log(mut self, key: Zeroizing<Vec<u8>>, sink: Box<dyn Write + Send>) -> Self {
+        self.audit_log = Some(AuditLog::new(key, sink));
+        self
+    }
+
+    /// Report operations taking longer than the given thresholds to the given callback.
+    ///
+    /// Only operations with a configured threshold are measured. Records contain the time
+    /// taken, the path depth and the node fetch statistics of the operation, but never any
+    /// keys or values.
+    ///
+    /// Inside enclaves the default time source never advances, so all operations take zero
+    /// time and only operations with a zero threshold are reported. Supply a clock via
+    /// `with_time_source` for slow operation logging to be useful there.
+    pub fn with_slow_op_logger(
+        mut self,
+        thresholds: SlowOpThresholds,
+        callback: SlowOpCallback,
+    ) -> Self {
+        self.slow_op_logger = Some(slow_ops::SlowOpLogger {
+            thresholds,
+            callback,
+        });
+        self
+    }
+
+    /// Set the time source used by all time-dependent behavior of the tree.
+    ///
+    /// If left unspecified, the system's monotonic clock is used, except inside enclaves
+    /// where a `LogicalClock` that is never advanced is used instead. In that case time
+    /// never passes for the tree, so a slow operation logger does not report anything unless
+    /// its thresholds are zero.
+    pub fn with_time_source(mut self, time_source: Box<dyn TimeSource>) -> Self {
+        self.time_source = Some(time_source);
+        self
+    }
+
     /// Commit the options set so far into a newly constructed tree instance.
     pub fn build(self, read_syncer: Box<dyn ReadSync>) -> Tree {
         assert!(
@@ -107,7 +213,13 @@ impl Builder {
                 );
             }
         }
-        Tree::new(read_syncer, &self.options)
+        let mut tree = Tree::new(read_syncer, &self.options);
+        tree.audit_log = self.audit_log;
+        tree.slow_op_logger = self.slow_op_logger;
+        if let Some(time_source) = self.time_source {
+            tree.time_source = time_source;
+        }
+        tree
     }
 }
 
@@ -115,6 +227,13 @@ impl Builder {
 pub struct Tree {
     pub(crate) cache: RefCell<Box<LRUCache>>,
     pub(crate) root_type: RootType,
+    pub(crate) audit_log: Option<AuditLog>,
+    pub(crate) hot_key_limit: usize,
+    pub(crate) commit_stats: Option<CommitStats>,
+    pub(crate) proof_node_budget: usize,
+    pub(crate) guard_panics: bool,
+    pub(crate) slow_op_logger: Option<slow_ops::SlowOpLogger>,
+    pub(crate) time_source: Box<dyn TimeSource>,
 }
 
 // Tree is Send as long as ownership of internal Rcs cannot leak out via any of its methods.
@@ -136,6 +255,13 @@ impl Tree {
                 root_type,
             )),
             root_type,
+            audit_log: None,
+            hot_key_limit: opts.hot_key_limit,
+            commit_stats: None,
+            proof_node_budget: opts.proof_node_budget,
+            guard_panics: opts.guard_panics,
+            slow_op_logger: None,
+            time_source: clock::default_time_source(),
         };
 
         if let Some(root) = opts.root {
@@ -201,6 +327,8 @@ impl mkvs::FallibleMKVS for Tree {
 #[cfg(test)]
 mod node_test;
 #[cfg(test)]
+mod perf_budget_test;
+#[cfg(test)]
 mod tree_bench;
 #[cfg(test)]
 mod tree_test;
@@ -433,6 +433,11 @@ impl KeyTrait for Key {
             }
         }
 
+        // Clean any bits past key_len which were shifted into the suffix.
+        if (key_len - split_point) % 8 != 0 {
+            suffix[suffix_len - 1] &= 0xff << (8 - (key_len - split_point) % 8);
+        }
+
         (prefix, suffix)
     }
 
@@ -445,16 +450,28 @@ impl KeyTrait for Key {
         let mut new_key: Key = vec![0; (key_len + k2_len).to_bytes()];
         new_key[..key_len_bytes].clone_from_slice(&self[..key_len_bytes]);
 
-        for i in 0..k2.len() {
+        // Clean the remainder of the last byte, so it doesn't get mixed with k2.
+        if key_len % 8 != 0 {
+            new_key[key_len_bytes - 1] &= 0xff << (8 - key_len % 8);
+        }
+
+        // Only consider bytes of k2 that are within k2_len.
+        let k2_bytes = k2_len.to_bytes().min(k2.len());
+        for i in 0..k2_bytes {
+            let mut b = k2[i];
+            if i == k2_bytes - 1 && k2_len % 8 != 0 {
+                b &= 0xff << (8 - k2_len % 8);
+            }
+
             // First set the right chunk of the previous byte
             if key_len % 8 != 0 && key_len_bytes > 0 {
-                new_key[key_len_bytes + i - 1] |= k2[i] >> (key_len % 8);
+                new_key[key_len_bytes + i - 1] |= b >> (key_len % 8);
             }
             // ...and the next left chunk, if we haven't reached the end of newKey
             // yet.
             if key_len_bytes + i < new_key.len() {
                 // another mod 8 to prevent bit shifting for 8 bits
-                new_key[key_len_bytes + i] |= k2[i] << ((8 - key_len % 8) % 8);
+                new_key[key_len_bytes + i] |= b << ((8 - key_len % 8) % 8);
             }
         }
 
@@ -463,7 +480,9 @@ impl KeyTrait for Key {
 
     fn append_bit(&self, key_len: Depth, val: bool) -> Key {
         let mut new_key: Key = vec![0; (key_len + 1).to_bytes()];
-        new_key[..self.len()].clone_from_slice(self);
+        // Ignore any bytes past the new key length.
+        let copy_len = self.len().min(new_key.len());
+        new_key[..copy_len].clone_from_slice(&self[..copy_len]);
 
         if val {
             new_key[key_len as usize / 8] |= 0x80 >> (key_len % 8)
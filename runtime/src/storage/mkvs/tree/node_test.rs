@@ -1,5 +1,7 @@
 use std::str::FromStr;
 
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
 use crate::storage::mkvs::{marshal::*, tree::*};
 
 #[test]
@@ -92,6 +94,60 @@ fn test_serialization_internal() {
     assert_eq!(false, decoded_int_node.right.borrow().node.is_some());
 }
 
+#[test]
+fn test_unmarshal_malformed() {
+    let leaf_node = LeafNode {
+        key: b"a golden key".to_vec(),
+        value: b"value".to_vec(),
+        ..Default::default()
+    };
+    let internal_node = InternalNode {
+        label: b"abc".to_vec(),
+        label_bit_length: 23,
+        leaf_node: NodePointer::from_node(NodeBox::Leaf(leaf_node.copy())),
+        left: NodePointer::null_ptr(),
+        right: NodePointer::null_ptr(),
+        ..Default::default()
+    };
+
+    // Every truncation of a valid node must be rejected or decode a shorter node.
+    for marshaled in &[
+        leaf_node.marshal_binary().expect("marshal"),
+        internal_node.marshal_binary().expect("marshal"),
+        internal_node.compact_marshal_binary(1).expect("marshal"),
+    ] {
+        for i in 0..marshaled.len() {
+            let mut node = NodeBox::default();
+            if let Ok(n) = node.unmarshal_binary(&marshaled[..i]) {
+                assert!(n <= i);
+            }
+        }
+    }
+
+    // Length fields exceeding the input.
+    let cases: &[&[u8]] = &[
+        // Internal node with maximum label length.
+        &[0x01, 0xff, 0xff, 0x00],
+        // Leaf node with maximum key length.
+        &[0x00, 0xff, 0xff, 0x00, 0x00, 0x00, 0x00],
+        // Leaf node with maximum value length.
+        &[0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff],
+        // Internal node with a leaf node with maximum value length.
+        &[0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff],
+        // Unknown node kind.
+        &[0x03, 0x00, 0x00, 0x00],
+        &[],
+    ];
+    for data in cases {
+        let mut node = NodeBox::default();
+        assert!(
+            node.unmarshal_binary(data).is_err(),
+            "malformed node {:?} should be rejected",
+            data
+        );
+    }
+}
+
 #[test]
 fn test_hash_leaf() {
     let mut leaf_node = LeafNode {
@@ -255,3 +311,85 @@ fn test_key_common_prefix_len() {
     assert_eq!(12, key.common_prefix_len(13, &vec![0xab, 0xcd], 12));
     assert_eq!(12, key.common_prefix_len(12, &vec![0xab, 0xcd], 13));
 }
+
+/// Expand the first `len` bits of the key.
+fn key_bits(key: &Key, len: Depth) -> Vec<bool> {
+    (0..len).map(|i| key.get_bit(i)).collect()
+}
+
+/// Pack bits into a key, with any unused bits of the last byte cleared.
+fn bits_key(bits: &[bool]) -> Key {
+    let mut key: Key = vec![0; (bits.len() as Depth).to_bytes()];
+    for (i, bit) in bits.iter().enumerate() {
+        if *bit {
+            key[i / 8] |= 0x80 >> (i % 8);
+        }
+    }
+    key
+}
+
+#[test]
+fn test_key_split_merge_random() {
+    let mut rng = StdRng::seed_from_u64(0);
+
+    for _ in 0..10_000 {
+        // Keys may be longer than their bit length and contain garbage past it.
+        let key: Key = (0..rng.gen_range(0..8)).map(|_| rng.gen()).collect();
+        let key_len = rng.gen_range(0..=key.bit_length());
+        let split_point = rng.gen_range(0..=key_len);
+        let bits = key_bits(&key, key_len);
+
+        let (prefix, suffix) = key.split(split_point, key_len);
+        assert_eq!(prefix, bits_key(&bits[..split_point as usize]));
+        assert_eq!(suffix, bits_key(&bits[split_point as usize..]));
+
+        // Splitting and merging must reconstruct the original key.
+        let merged = prefix.merge(split_point, &suffix, key_len - split_point);
+        assert_eq!(merged, bits_key(&bits));
+
+        // Merging must ignore garbage past the given lengths.
+        let mut other: Key = (0..rng.gen_range(0..8)).map(|_| rng.gen()).collect();
+        let other_len = rng.gen_range(0..=other.bit_length());
+        let merged = key.merge(key_len, &other, other_len);
+        let mut expected = bits.clone();
+        expected.extend(key_bits(&other, other_len));
+        assert_eq!(merged, bits_key(&expected));
+
+        // Appending a bit only changes that bit.
+        let bit = rng.gen();
+        let appended = key.append_bit(key_len, bit);
+        assert_eq!(appended.len(), (key_len + 1).to_bytes());
+        let mut expected = bits.clone();
+        expected.push(bit);
+        assert_eq!(key_bits(&appended, key_len + 1), expected);
+
+        // Common prefix of unrelated keys.
+        let cp_len = key.common_prefix_len(key_len, &other, other_len);
+        let other_bits = key_bits(&other, other_len);
+        let expected = bits
+            .iter()
+            .zip(other_bits.iter())
+            .take_while(|(a, b)| a == b)
+            .count();
+        assert_eq!(cp_len as usize, expected);
+        assert!(cp_len <= key_len && cp_len <= other_len);
+
+        // Common prefix with a key that shares a prefix.
+        other = merged_prefix(&key, split_point, &other, other_len);
+        let cp_len = key.common_prefix_len(key_len, &other, split_point + other_len);
+        assert!(cp_len >= split_point);
+        assert!(cp_len <= key_len && cp_len <= split_point + other_len);
+        assert_eq!(
+            other.common_prefix_len(split_point + other_len, &key, key_len),
+            cp_len
+        );
+    }
+}
+
+/// Build a key consisting of the first `prefix_len` bits of `prefix` followed by `suffix`.
+fn merged_prefix(prefix: &Key, prefix_len: Depth, suffix: &Key, suffix_len: Depth) -> Key {
+    prefix
+        .split(prefix_len, prefix_len)
+        .0
+        .merge(prefix_len, suffix, suffix_len)
+}
@@ -1,4 +1,5 @@
 use std::{
+    borrow::Borrow,
     collections::{btree_map, BTreeMap, HashSet},
     iter::Peekable,
 };
@@ -7,9 +8,44 @@ use anyhow::{Error, Result};
 
 use crate::{
     common::{crypto::hash::Hash, namespace::Namespace},
-    storage::mkvs::{self, tree::Key, Proof},
+    storage::mkvs::{
+        self,
+        tree::{Key, Tree, TreeError, Witness},
+        Proof,
+    },
 };
 
+/// Policy for resolving conflicts between a write log and local uncommitted changes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Fail without applying any entries.
+    Fail,
+    /// Keep the local changes and skip the conflicting write log entries.
+    PreferLocal,
+    /// Overwrite the local changes with the conflicting write log entries.
+    PreferLog,
+}
+
+/// A write log entry for a key with a different local uncommitted change.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WriteConflict {
+    /// The conflicting key.
+    pub key: Vec<u8>,
+    /// The local uncommitted value (`None` if the key was removed).
+    pub local: Option<Vec<u8>>,
+    /// The value in the write log (`None` if the key was removed).
+    pub log: Option<Vec<u8>>,
+}
+
+/// Result of applying a write log with `OverlayTree::apply_write_log_checked`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ApplyReport {
+    /// Number of write log entries that were applied.
+    pub applied: usize,
+    /// All conflicts, in write log order.
+    pub conflicts: Vec<WriteConflict>,
+}
+
 /// A key-value tree overlay that holds all updates in memory and only commits them if requested.
 /// This can be used to create snapshots that can be discarded.
 ///
@@ -19,6 +55,7 @@ pub struct OverlayTree<T: mkvs::FallibleMKVS> {
     inner: T,
     overlay: BTreeMap<Vec<u8>, Vec<u8>>,
     dirty: HashSet<Vec<u8>>,
+    skip_unchanged: bool,
 }
 
 impl<T: mkvs::FallibleMKVS> OverlayTree<T> {
@@ -28,9 +65,19 @@ impl<T: mkvs::FallibleMKVS> OverlayTree<T> {
             inner,
             overlay: BTreeMap::new(),
             dirty: HashSet::new(),
+            skip_unchanged: false,
         }
     }
 
+    /// Configure whether inserts of a value equal to the value stored in the inner tree should
+    /// be skipped, so that they produce no write log entries and no node rewrites on commit.
+    ///
+    /// This is disabled by default, in which case every insert is written.
+    pub fn with_skip_unchanged(mut self, skip_unchanged: bool) -> Self {
+        self.skip_unchanged = skip_unchanged;
+        self
+    }
+
     /// Get an existing key.
     pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
         // For dirty values, check the overlay.
@@ -56,6 +103,20 @@ impl<T: mkvs::FallibleMKVS> OverlayTree<T> {
     pub fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>> {
         let previous = self.get(key)?;
 
+        if self.skip_unchanged {
+            let current = if self.dirty.contains(key) {
+                self.inner.get(key)?
+            } else {
+                previous.clone()
+            };
+            if current.as_deref() == Some(value) {
+                // Revert to the value in the inner tree.
+                self.overlay.remove(key);
+                self.dirty.remove(key);
+                return Ok(previous);
+            }
+        }
+
         self.overlay.insert(key.to_owned(), value.to_owned());
         self.dirty.insert(key.to_owned());
 
@@ -79,6 +140,54 @@ impl<T: mkvs::FallibleMKVS> OverlayTree<T> {
         Ok(value)
     }
 
+    /// Apply a write log on top of any local uncommitted changes.
+    ///
+    /// A write log entry conflicts when its key has a local uncommitted change to a different
+    /// value. Conflicts are resolved according to the given policy. With `ConflictPolicy::Fail`,
+    /// a `TreeError::WriteLogConflict` listing all conflicts is returned and the overlay is left
+    /// unchanged.
+    pub fn apply_write_log_checked(
+        &mut self,
+        write_log: &mkvs::WriteLog,
+        policy: ConflictPolicy,
+    ) -> Result<ApplyReport> {
+        let mut report = ApplyReport::default();
+        for entry in write_log {
+            if !self.dirty.contains(&entry.key) {
+                continue;
+            }
+            let local = self.overlay.get(&entry.key).cloned();
+            if local != entry.value {
+                report.conflicts.push(WriteConflict {
+                    key: entry.key.clone(),
+                    local,
+                    log: entry.value.clone(),
+                });
+            }
+        }
+        if policy == ConflictPolicy::Fail && !report.conflicts.is_empty() {
+            return Err(TreeError::WriteLogConflict(report.conflicts).into());
+        }
+
+        let conflicting: HashSet<&[u8]> = report
+            .conflicts
+            .iter()
+            .map(|conflict| conflict.key.as_slice())
+            .collect();
+        for entry in write_log {
+            if policy == ConflictPolicy::PreferLocal && conflicting.contains(entry.key.as_slice()) {
+                continue;
+            }
+            match entry.value {
+                Some(ref value) => self.insert(&entry.key, value)?,
+                None => self.remove(&entry.key)?,
+            };
+            report.applied += 1;
+        }
+
+        Ok(report)
+    }
+
     /// Return an iterator over the tree.
     pub fn iter(&self) -> OverlayTreeIterator<T> {
         OverlayTreeIterator::new(self)
@@ -87,16 +196,25 @@ impl<T: mkvs::FallibleMKVS> OverlayTree<T> {
     /// Commit any modifications to the underlying tree.
     pub fn commit(&mut self) -> Result<mkvs::WriteLog> {
         let mut log: mkvs::WriteLog = Vec::new();
+        self.commit_streaming(&mut log)?;
 
+        Ok(log)
+    }
+
+    /// Commit any modifications to the underlying tree, passing write log entries to the
+    /// given sink as they are committed instead of collecting them.
+    ///
+    /// Entries are emitted in the same order as in the write log returned by `commit`.
+    pub fn commit_streaming(&mut self, sink: &mut dyn mkvs::WriteLogSink) -> Result<()> {
         // Insert all items present in the overlay.
         for (key, value) in &self.overlay {
             self.inner.insert(key, value)?;
             self.dirty.remove(key);
 
-            log.push(mkvs::LogEntry {
+            sink.write(mkvs::LogEntry {
                 key: key.clone(),
                 value: Some(value.clone()),
-            });
+            })?;
         }
         self.overlay.clear();
 
@@ -104,14 +222,14 @@ impl<T: mkvs::FallibleMKVS> OverlayTree<T> {
         for key in &self.dirty {
             self.inner.remove(key)?;
 
-            log.push(mkvs::LogEntry {
+            sink.write(mkvs::LogEntry {
                 key: key.clone(),
                 value: None,
-            });
+            })?;
         }
         self.dirty.clear();
 
-        Ok(log)
+        sink.finish()
     }
 
     /// Commit any modifications to the underlying tree and then immediately commit the underlying
@@ -130,7 +248,30 @@ impl<T: mkvs::FallibleMKVS> OverlayTree<T> {
     }
 }
 
+impl<T: mkvs::FallibleMKVS + Borrow<Tree>> OverlayTree<T> {
+    /// Commit any modifications like `commit_both`, also returning a witness for the write log
+    /// which can be used to recompute the new root hash via `stateless_apply`.
+    ///
+    /// The witness covers all keys in the write log. The underlying tree, which may be owned
+    /// or borrowed, must not have any uncommitted changes.
+    pub fn commit_with_witness(
+        &mut self,
+        namespace: Namespace,
+        version: u64,
+    ) -> Result<(mkvs::WriteLog, Witness, Hash)> {
+        let keys: Vec<&[u8]> = self.dirty.iter().map(|key| key.as_slice()).collect();
+        let tree: &Tree = self.inner.borrow();
+        let witness = tree.witness(&keys)?;
+        let (write_log, root_hash) = self.commit_both(namespace, version)?;
+
+        Ok((write_log, witness, root_hash))
+    }
+}
+
 /// An iterator over the `OverlayTree`.
+///
+/// Entries of the overlay are merged with the entries of the inner tree in lexicographic key
+/// order, see `mkvs::Iterator`.
 pub struct OverlayTreeIterator<'tree, T: mkvs::FallibleMKVS> {
     tree: &'tree OverlayTree<T>,
 
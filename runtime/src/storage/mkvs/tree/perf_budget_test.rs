@@ -0,0 +1,166 @@
+//! Tests asserting hard upper bounds on the work done by common tree operations.
+//!
+//! The budgets are kept in a single table so that they are only revised deliberately. Allocation
+//! budgets need a counting global allocator and live in `runtime/tests/mkvs_allocations.rs`.
+use std::{cell::RefCell, rc::Rc};
+
+use crate::{
+    common::crypto::hash::Hash,
+    storage::mkvs::{
+        sync::{NoopReadSyncer, StatsCollector},
+        tree::{tree_test::LocalReadSyncer, *},
+    },
+};
+
+/// Number of keys in the tree used by the read workloads.
+const TREE_KEYS: usize = 10_000;
+/// Number of keys inserted by the commit workload.
+const COMMIT_KEYS: usize = 1_000;
+
+/// Maximum number of fetches for a get with a warm cache.
+const BUDGET_WARM_GET_FETCHES: usize = 0;
+/// Maximum number of fetches for a get with a cold cache, when each fetch returns one level.
+const BUDGET_COLD_GET_FETCHES: usize = 40;
+/// Maximum number of proof entries for a single key.
+const BUDGET_PROOF_ENTRIES: usize = 100;
+/// Maximum number of nodes rewritten when removing a single key.
+const BUDGET_REMOVE_REWRITES: usize = 40;
+/// Maximum number of dirty nodes after `COMMIT_KEYS` inserts under a shared prefix, which are
+/// all rewritten by the following commit (a leaf and an internal node per key, plus the path
+/// to the shared prefix).
+const BUDGET_COMMIT_DIRTY_NODES: usize = 2 * COMMIT_KEYS + 40;
+
+fn check_budget(name: &str, actual: usize, budget: usize) {
+    assert!(
+        actual <= budget,
+        "{}: actual {} exceeds budget {}",
+        name,
+        actual,
+        budget
+    );
+}
+
+fn generate_keys(count: usize, prefix: &str) -> Vec<Vec<u8>> {
+    (0..count)
+        .map(|i| format!("{}key {}", prefix, i).into_bytes())
+        .collect()
+}
+
+fn generate_tree(keys: &[Vec<u8>]) -> (Tree, Hash) {
+    let mut tree = Tree::builder()
+        .with_root_type(RootType::State)
+        .build(Box::new(NoopReadSyncer));
+    for key in keys {
+        tree.insert(key, b"value").expect("insert");
+    }
+    let hash = tree.commit(Default::default(), 0).expect("commit");
+
+    (tree, hash)
+}
+
+/// Build a tree which fetches all nodes from the given tree and counts the fetches.
+fn remote_tree(tree: &Tree, hash: Hash) -> Tree {
+    let positions = Rc::new(RefCell::new(Vec::new()));
+    Tree::builder()
+        .with_capacity(0, 0)
+        .with_root(Root {
+            root_type: RootType::State,
+            hash,
+            ..Default::default()
+        })
+        .build(Box::new(StatsCollector::new(Box::new(
+            LocalReadSyncer::new(tree, positions),
+        ))))
+}
+
+fn fetch_count(tree: &Tree) -> usize {
+    let cache = tree.cache.borrow();
+    let stats = cache
+        .get_read_syncer()
+        .as_any()
+        .downcast_ref::<StatsCollector>()
+        .expect("stats");
+    stats.sync_get_count + stats.sync_get_prefixes_count + stats.sync_iterate_count
+}
+
+fn dirty_node_count(tree: &Tree) -> usize {
+    let mut count = 0;
+    tree.walk_nodes(|ptr, _, _| {
+        // Clean subtrees cannot contain any dirty nodes.
+        if ptr.borrow().clean {
+            return Ok(false);
+        }
+        count += 1;
+        Ok(true)
+    })
+    .expect("walk_nodes");
+    count
+}
+
+#[test]
+fn test_budget_get() {
+    let keys = generate_keys(TREE_KEYS, "");
+    let (tree, hash) = generate_tree(&keys);
+    let remote_tree = remote_tree(&tree, hash);
+
+    let mut max_cold = 0;
+    for key in &keys {
+        let before = fetch_count(&remote_tree);
+        remote_tree.get(key).expect("get").expect("key exists");
+        max_cold = max_cold.max(fetch_count(&remote_tree) - before);
+    }
+    check_budget("cold get fetches", max_cold, BUDGET_COLD_GET_FETCHES);
+
+    let before = fetch_count(&remote_tree);
+    for key in &keys {
+        remote_tree.get(key).expect("get").expect("key exists");
+    }
+    check_budget(
+        "warm get fetches",
+        fetch_count(&remote_tree) - before,
+        BUDGET_WARM_GET_FETCHES,
+    );
+}
+
+#[test]
+fn test_budget_proof() {
+    let keys = generate_keys(TREE_KEYS, "");
+    let (tree, _) = generate_tree(&keys);
+
+    let mut max_entries = 0;
+    for key in keys.iter().step_by(97) {
+        let proof = tree.get_proof(key).expect("get_proof").expect("key exists");
+        max_entries = max_entries.max(proof.entries.len());
+    }
+    check_budget("proof entries", max_entries, BUDGET_PROOF_ENTRIES);
+}
+
+#[test]
+fn test_budget_remove() {
+    let keys = generate_keys(TREE_KEYS, "");
+    let (mut tree, _) = generate_tree(&keys);
+
+    let mut max_rewrites = 0;
+    for (i, key) in keys.iter().enumerate().step_by(97) {
+        tree.remove(key).expect("remove");
+        max_rewrites = max_rewrites.max(dirty_node_count(&tree));
+        tree.commit(Default::default(), i as u64 + 1)
+            .expect("commit");
+    }
+    check_budget("remove rewrites", max_rewrites, BUDGET_REMOVE_REWRITES);
+}
+
+#[test]
+fn test_budget_commit() {
+    let (mut tree, _) = generate_tree(&generate_keys(TREE_KEYS, ""));
+
+    for key in generate_keys(COMMIT_KEYS, "shared/prefix/") {
+        tree.insert(&key, b"value").expect("insert");
+    }
+    check_budget(
+        "commit dirty nodes",
+        dirty_node_count(&tree),
+        BUDGET_COMMIT_DIRTY_NODES,
+    );
+    tree.commit(Default::default(), 1).expect("commit");
+}
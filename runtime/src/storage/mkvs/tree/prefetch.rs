@@ -40,4 +40,22 @@ impl Tree {
             .borrow_mut()
             .remote_sync(pending_root, FetcherSyncGetPrefixes::new(prefixes, limit))
     }
+
+    /// Populate the in-memory tree with all nodes in the top `levels` levels of the tree,
+    /// where the root node is at level zero. Returns the number of nodes loaded.
+    ///
+    /// As these nodes are on the path to every key, this amortizes their fetches across all
+    /// subsequent reads, as long as the cache is large enough to keep them.
+    pub fn warm_top(&self, levels: usize) -> Result<usize> {
+        if levels == 0 {
+            return Ok(0);
+        }
+
+        let mut loaded = 0;
+        self.walk_nodes(|_, _, depth| {
+            loaded += 1;
+            Ok(depth + 1 < levels)
+        })?;
+        Ok(loaded)
+    }
 }
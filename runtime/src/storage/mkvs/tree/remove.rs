@@ -1,18 +1,34 @@
-use anyhow::Result;
-
-use crate::storage::mkvs::{
-    cache::Cache,
-    tree::{
-        Depth, Key, KeyTrait, NodeBox, NodeKind, NodePointer, NodePtrRef, NodeRef, Tree, Value,
+use anyhow::{anyhow, Result};
+
+use crate::{
+    common::crypto::hash::Hash,
+    storage::mkvs::{
+        self,
+        cache::Cache,
+        tree::{
+            AuditOp, Depth, Key, KeyTrait, NodeBox, NodeKind, NodePointer, NodePtrRef, NodeRef,
+            SlowOp, Tree, Value,
+        },
     },
 };
 
-use super::lookup::FetcherSyncGet;
+use super::{guard, lookup::FetcherSyncGet, slow_ops::SlowOpTimer};
 
 impl Tree {
     /// Remove entry with given key, returning the value at the key if the key was previously
     /// in the database.
     pub fn remove(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let mut timer = self.start_slow_op(SlowOp::Remove);
+        let result = guard::guarded(self.guard_panics, || self._remove_top(key, timer.as_mut()));
+        self.finish_slow_op(timer);
+        result
+    }
+
+    fn _remove_top(
+        &mut self,
+        key: &[u8],
+        timer: Option<&mut SlowOpTimer>,
+    ) -> Result<Option<Vec<u8>>> {
         let boxed_key = key.to_vec();
         let pending_root = self.cache.borrow().get_pending_root();
 
@@ -21,10 +37,54 @@ impl Tree {
 
         let (new_root, _, old_val) = self._remove(pending_root, 0, &boxed_key)?;
         self.cache.borrow_mut().set_pending_root(new_root);
+        self.record_slow_op_depth(timer, &boxed_key);
+
+        #[cfg(feature = "debug-invariants")]
+        self.check_invariants("remove", key);
+
+        // Only record the operation once it has succeeded.
+        if let Some(audit_log) = self.audit_log.as_mut() {
+            audit_log.append(AuditOp::Remove, Hash::digest_bytes(key), Hash::empty_hash())?;
+        }
 
         Ok(old_val)
     }
 
+    /// Retain only the entries for which the predicate returns `true`, removing all others.
+    /// Returns the number of removed entries.
+    ///
+    /// Entries, including any uncommitted updates, are visited in key order. The removals are
+    /// not committed.
+    pub fn retain<F>(&mut self, mut f: F) -> Result<usize>
+    where
+        F: FnMut(&[u8], &[u8]) -> bool,
+    {
+        let mut remove_keys = Vec::new();
+        {
+            use mkvs::Iterator;
+
+            let mut it = self.iter();
+            it.rewind();
+            while it.is_valid() {
+                let key = it.get_key().as_ref().expect("iterator is valid");
+                let value = it.get_value().as_ref().expect("iterator is valid");
+                if !f(key, value) {
+                    remove_keys.push(key.clone());
+                }
+                Iterator::next(&mut it);
+            }
+            if let Some(error) = it.error() {
+                return Err(anyhow!("mkvs: failed to iterate tree: {}", error));
+            }
+        }
+
+        for key in &remove_keys {
+            self.remove(key)?;
+        }
+
+        Ok(remove_keys.len())
+    }
+
     fn _remove(
         &mut self,
         ptr: NodePtrRef,
@@ -0,0 +1,65 @@
+use anyhow::Result;
+
+use crate::{
+    common::crypto::hash::Hash,
+    storage::mkvs::tree::{Depth, Key, NodeBox, Tree},
+};
+
+/// A subtree that could not be reached while salvaging a tree.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MissingSubtree {
+    /// Hash of the missing subtree root node.
+    pub hash: Hash,
+    /// Key prefix covered by the missing subtree.
+    pub prefix: Key,
+    /// Length of the key prefix in bits.
+    pub prefix_bit_length: Depth,
+}
+
+/// Result of salvaging a tree.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SalvageReport {
+    /// Number of recovered key/value pairs.
+    pub recovered: usize,
+    /// Subtrees that could not be reached.
+    ///
+    /// Nodes do not record the number of keys below them, so the number of lost keys
+    /// cannot be determined.
+    pub missing: Vec<MissingSubtree>,
+}
+
+impl Tree {
+    /// Walk the tree and pass every reachable key/value pair to the given callback, in key
+    /// order.
+    ///
+    /// Unlike iteration, nodes which cannot be fetched do not abort the walk. Instead, each
+    /// unreachable subtree is recorded in the returned report.
+    pub fn salvage<F>(&self, mut out: F) -> Result<SalvageReport>
+    where
+        F: FnMut(Vec<u8>, Vec<u8>),
+    {
+        let mut report = SalvageReport::default();
+        let mut missing = Vec::new();
+
+        self.walk_nodes_with_missing(
+            |_, node_ref, _| {
+                if let NodeBox::Leaf(ref n) = *node_ref.borrow() {
+                    out(n.key.clone(), n.value.clone());
+                    report.recovered += 1;
+                }
+                Ok(true)
+            },
+            |ptr, path, path_bits, _| {
+                missing.push(MissingSubtree {
+                    hash: ptr.borrow().hash,
+                    prefix: path.clone(),
+                    prefix_bit_length: path_bits,
+                });
+                Ok(())
+            },
+        )?;
+
+        report.missing = missing;
+        Ok(report)
+    }
+}
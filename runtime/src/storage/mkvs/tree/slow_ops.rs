@@ -0,0 +1,368 @@
+use std::time::Duration;
+
+use crate::storage::mkvs::{
+    cache::{Cache, FetchCounters},
+    tree::{Key, KeyTrait, NodeBox, Tree},
+};
+
+/// Type of a tree operation measured by the slow operation logger.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SlowOp {
+    Get,
+    Insert,
+    Remove,
+    Commit,
+}
+
+/// Per-operation thresholds above which operations are reported as slow.
+///
+/// Operations without a threshold are not measured at all. Only `Tree::get` is measured as a
+/// get; `get_many`, `get_map`, `get_observed`, `get_with_path_hashes`, `get_proof`,
+/// `get_with_proof` and `prove_pair` are never measured.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SlowOpThresholds {
+    pub get: Option<Duration>,
+    pub insert: Option<Duration>,
+    pub remove: Option<Duration>,
+    pub commit: Option<Duration>,
+}
+
+impl SlowOpThresholds {
+    fn for_op(&self, op: SlowOp) -> Option<Duration> {
+        match op {
+            SlowOp::Get => self.get,
+            SlowOp::Insert => self.insert,
+            SlowOp::Remove => self.remove,
+            SlowOp::Commit => self.commit,
+        }
+    }
+}
+
+/// A record of a slow operation.
+///
+/// Records never include any keys or values.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SlowOpRecord {
+    /// The operation.
+    pub op: SlowOp,
+    /// Time taken by the operation.
+    pub elapsed: Duration,
+    /// Number of locally available nodes on the path to the key after the operation,
+    /// including the leaf node (zero for commits).
+    pub depth: usize,
+    /// Number of proofs fetched via the read syncer during the operation.
+    pub fetches: usize,
+    /// Total size of proof entries fetched during the operation.
+    pub fetched_bytes: usize,
+    /// Number of nodes dereferenced from the cache during the operation.
+    pub cache_hits: usize,
+}
+
+/// Callback invoked with every slow operation record.
+pub type SlowOpCallback = Box<dyn Fn(&SlowOpRecord) + Send>;
+
+pub(crate) struct SlowOpLogger {
+    pub(super) thresholds: SlowOpThresholds,
+    pub(super) callback: SlowOpCallback,
+}
+
+/// A measurement of an operation in progress.
+pub(super) struct SlowOpTimer {
+    op: SlowOp,
+    threshold: Duration,
+    start: Duration,
+    counters: FetchCounters,
+    pub(super) depth: usize,
+}
+
+impl Tree {
+    /// Start measuring the given operation, if it has a configured threshold.
+    pub(super) fn start_slow_op(&self, op: SlowOp) -> Option<SlowOpTimer> {
+        let threshold = self.slow_op_logger.as_ref()?.thresholds.for_op(op)?;
+        Some(SlowOpTimer {
+            op,
+            threshold,
+            start: self.time_source.now(),
+            counters: self.cache.borrow().fetch_counters(),
+            depth: 0,
+        })
+    }
+
+    /// Record the depth of the path to the given key in a running measurement.
+    pub(super) fn record_slow_op_depth(&self, timer: Option<&mut SlowOpTimer>, key: &Key) {
+        if let Some(timer) = timer {
+            timer.depth = self.path_depth(key);
+        }
+    }
+
+    /// Count the locally available nodes on the path to the given key, without fetching.
+    fn path_depth(&self, key: &Key) -> usize {
+        let mut ptr = self.cache.borrow().get_pending_root();
+        let mut bit_depth = 0;
+        let mut depth = 0;
+        loop {
+            let node_ref = match ptr.borrow().node {
+                Some(ref node_ref) => node_ref.clone(),
+                None => return depth,
+            };
+            depth += 1;
+
+            let next = match *node_ref.borrow() {
+                NodeBox::Internal(ref n) => {
+                    bit_depth += n.label_bit_length;
+                    if key.bit_length() == bit_depth {
+                        n.leaf_node.clone()
+                    } else if key.bit_length() < bit_depth {
+                        return depth;
+                    } else if key.get_bit(bit_depth) {
+                        n.right.clone()
+                    } else {
+                        n.left.clone()
+                    }
+                }
+                NodeBox::Leaf(_) => return depth,
+            };
+            ptr = next;
+        }
+    }
+
+    /// Finish measuring an operation, reporting it in case it exceeded its threshold.
+    pub(super) fn finish_slow_op(&self, timer: Option<SlowOpTimer>) {
+        let timer = match timer {
+            Some(timer) => timer,
+            None => return,
+        };
+        let elapsed = self.time_source.now().saturating_sub(timer.start);
+        if elapsed < timer.threshold {
+            return;
+        }
+
+        let counters = self.cache.borrow().fetch_counters();
+        let record = SlowOpRecord {
+            op: timer.op,
+            elapsed,
+            depth: timer.depth,
+            fetches: counters.fetches - timer.counters.fetches,
+            fetched_bytes: counters.fetched_bytes - timer.counters.fetched_bytes,
+            cache_hits: counters.cache_hits - timer.counters.cache_hits,
+        };
+        if let Some(logger) = self.slow_op_logger.as_ref() {
+            (logger.callback)(&record);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        any::Any,
+        cell::RefCell,
+        rc::Rc,
+        sync::{Arc, Mutex},
+    };
+
+    use anyhow::Result;
+
+    use super::*;
+    use crate::{
+        common::crypto::hash::Hash,
+        storage::mkvs::{
+            sync::{
+                GetPrefixesRequest, GetRequest, IterateRequest, NoopReadSyncer, ProofResponse,
+                ReadSync,
+            },
+            tree::{tree_test::LocalReadSyncer, LogicalClock, Root, RootType},
+        },
+    };
+
+    const FETCH_DELAY: Duration = Duration::from_millis(5);
+
+    /// A read syncer which advances the clock on every request and records the size of each
+    /// response.
+    struct SlowReadSyncer {
+        inner: LocalReadSyncer,
+        clock: LogicalClock,
+        fetched_bytes: Arc<Mutex<Vec<usize>>>,
+    }
+
+    impl SlowReadSyncer {
+        fn respond(&self, rsp: Result<ProofResponse>) -> Result<ProofResponse> {
+            self.clock.advance(FETCH_DELAY);
+            let rsp = rsp?;
+            self.fetched_bytes.lock().unwrap().push(
+                rsp.proof
+                    .entries
+                    .iter()
+                    .flatten()
+                    .map(|entry| entry.len())
+                    .sum(),
+            );
+            Ok(rsp)
+        }
+    }
+
+    impl ReadSync for SlowReadSyncer {
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+
+        fn sync_get(&mut self, request: GetRequest) -> Result<ProofResponse> {
+            let rsp = self.inner.sync_get(request);
+            self.respond(rsp)
+        }
+
+        fn sync_get_prefixes(&mut self, request: GetPrefixesRequest) -> Result<ProofResponse> {
+            let rsp = self.inner.sync_get_prefixes(request);
+            self.respond(rsp)
+        }
+
+        fn sync_iterate(&mut self, request: IterateRequest) -> Result<ProofResponse> {
+            let rsp = self.inner.sync_iterate(request);
+            self.respond(rsp)
+        }
+    }
+
+    fn generate_tree() -> (Tree, Hash) {
+        let mut tree = Tree::builder()
+            .with_root_type(RootType::State)
+            .build(Box::new(NoopReadSyncer));
+        for i in 0..100 {
+            tree.insert(format!("key {}", i).as_bytes(), b"value")
+                .expect("insert");
+        }
+        let hash = tree.commit(Default::default(), 0).expect("commit");
+        (tree, hash)
+    }
+
+    fn recording_callback() -> (Arc<Mutex<Vec<SlowOpRecord>>>, SlowOpCallback) {
+        let records = Arc::new(Mutex::new(Vec::new()));
+        let sink = records.clone();
+        (
+            records,
+            Box::new(move |record| sink.lock().unwrap().push(record.clone())),
+        )
+    }
+
+    #[test]
+    fn test_slow_op_get() {
+        let (tree, hash) = generate_tree();
+        let fetched_bytes = Arc::new(Mutex::new(Vec::new()));
+        let (records, callback) = recording_callback();
+        let clock = LogicalClock::new();
+        let remote_tree = Tree::builder()
+            .with_root(Root {
+                root_type: RootType::State,
+                hash,
+                ..Default::default()
+            })
+            .with_slow_op_logger(
+                SlowOpThresholds {
+                    get: Some(FETCH_DELAY),
+                    ..Default::default()
+                },
+                callback,
+            )
+            .with_time_source(Box::new(clock.clone()))
+            .build(Box::new(SlowReadSyncer {
+                inner: LocalReadSyncer::new(&tree, Rc::new(RefCell::new(Vec::new()))),
+                clock,
+                fetched_bytes: fetched_bytes.clone(),
+            }));
+
+        // A cold lookup needs to fetch nodes and is reported.
+        remote_tree
+            .get(b"key 42")
+            .expect("get")
+            .expect("key exists");
+        let (_, path) = remote_tree
+            .get_with_path_hashes(b"key 42")
+            .expect("get_with_path_hashes");
+        {
+            let records = records.lock().unwrap();
+            let fetched_bytes = fetched_bytes.lock().unwrap();
+            assert_eq!(records.len(), 1);
+            let record = &records[0];
+            assert_eq!(record.op, SlowOp::Get);
+            assert_eq!(record.elapsed, FETCH_DELAY * record.fetches as u32);
+            assert!(record.fetches > 0);
+            assert_eq!(record.fetches, fetched_bytes.len());
+            assert_eq!(record.fetched_bytes, fetched_bytes.iter().sum::<usize>());
+            // Each node on the path is either fetched or found in the cache.
+            assert_eq!(record.cache_hits + record.fetches, path.len());
+            assert_eq!(record.depth, path.len());
+        }
+
+        // A warm lookup is fast and is not reported.
+        remote_tree
+            .get(b"key 42")
+            .expect("get")
+            .expect("key exists");
+        assert_eq!(records.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_slow_op_cache_hits() {
+        let (records, callback) = recording_callback();
+        let mut tree = Tree::builder()
+            .with_root_type(RootType::State)
+            .with_slow_op_logger(
+                SlowOpThresholds {
+                    get: Some(Duration::ZERO),
+                    insert: Some(Duration::ZERO),
+                    remove: Some(Duration::ZERO),
+                    commit: Some(Duration::ZERO),
+                },
+                callback,
+            )
+            .build(Box::new(NoopReadSyncer));
+
+        tree.insert(b"foo", b"bar").expect("insert");
+        tree.insert(b"moo", b"boo").expect("insert");
+        tree.commit(Default::default(), 0).expect("commit");
+        tree.remove(b"moo").expect("remove");
+        records.lock().unwrap().clear();
+
+        // All dereferenced nodes are in memory.
+        tree.get(b"foo").expect("get");
+        let (_, path) = tree
+            .get_with_path_hashes(b"foo")
+            .expect("get_with_path_hashes");
+
+        let records = records.lock().unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].op, SlowOp::Get);
+        assert_eq!(records[0].fetches, 0);
+        assert_eq!(records[0].fetched_bytes, 0);
+        assert_eq!(records[0].cache_hits, path.len());
+        assert_eq!(records[0].depth, path.len());
+    }
+
+    #[test]
+    fn test_slow_op_ops() {
+        let (records, callback) = recording_callback();
+        let mut tree = Tree::builder()
+            .with_root_type(RootType::State)
+            .with_slow_op_logger(
+                SlowOpThresholds {
+                    insert: Some(Duration::ZERO),
+                    remove: Some(Duration::ZERO),
+                    commit: Some(Duration::ZERO),
+                    ..Default::default()
+                },
+                callback,
+            )
+            .build(Box::new(NoopReadSyncer));
+
+        tree.insert(b"foo", b"bar").expect("insert");
+        tree.get(b"foo").expect("get");
+        tree.remove(b"foo").expect("remove");
+        tree.commit(Default::default(), 0).expect("commit");
+
+        let records = records.lock().unwrap();
+        let ops: Vec<SlowOp> = records.iter().map(|r| r.op).collect();
+        assert_eq!(ops, vec![SlowOp::Insert, SlowOp::Remove, SlowOp::Commit]);
+        // The inserted key is the root leaf, the removal leaves an empty tree.
+        let depths: Vec<usize> = records.iter().map(|r| r.depth).collect();
+        assert_eq!(depths, vec![1, 0, 0]);
+    }
+}
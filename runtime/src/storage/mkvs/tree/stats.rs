@@ -0,0 +1,440 @@
+use std::{any::Any, cell::RefCell, collections::BTreeMap, io::Write, rc::Rc};
+
+use anyhow::Result;
+
+use crate::storage::mkvs::{
+    cache::Cache,
+    marshal::Marshal,
+    sync::{GetPrefixesRequest, GetRequest, IterateRequest, ProofResponse, ReadSync},
+    tree::{Depth, Key, KeyTrait, NodeBox, NodePtrRef, NodeRef, Root, Tree},
+};
+
+use super::iterator::FetcherSyncIterate;
+
+/// A tree is considered skewed if its maximum leaf depth exceeds the depth of a perfectly
+/// balanced tree with the same number of leaves by more than this factor.
+const BALANCE_SKEW_FACTOR: usize = 4;
+
+/// Difference in storage footprint between two trees.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StorageDelta {
+    /// Number of nodes present in the new tree but not in the old one.
+    pub added_nodes: usize,
+    /// Total encoded size of added nodes in bytes.
+    pub added_bytes: usize,
+    /// Number of nodes present in the old tree but not in the new one.
+    pub removed_nodes: usize,
+    /// Total encoded size of removed nodes in bytes.
+    pub removed_bytes: usize,
+}
+
+/// A key prefix consisting of whole nibbles (4-bit halves of key bytes), one per element.
+pub type NibblePrefix = Vec<u8>;
+
+/// Distribution of leaf depths in a tree.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BalanceReport {
+    /// Number of leaves in the tree.
+    pub leaves: usize,
+    /// Minimum leaf depth (number of internal nodes on the path to the leaf).
+    pub min_depth: usize,
+    /// Maximum leaf depth.
+    pub max_depth: usize,
+    /// Mean leaf depth.
+    pub mean_depth: f64,
+    /// Number of leaves at each depth, indexed by depth.
+    pub histogram: Vec<usize>,
+    /// Whether the tree is badly skewed.
+    ///
+    /// This usually means that many keys are prefixes of other keys, resulting in long
+    /// chains of internal nodes. Hashing keys before insertion avoids this.
+    pub skewed: bool,
+}
+
+impl Tree {
+    /// Compute the distribution of leaf depths in the tree with a single traversal.
+    pub fn balance_report(&self) -> Result<BalanceReport> {
+        let mut report = BalanceReport::default();
+        let mut total_depth = 0;
+
+        self.walk_nodes(|_, node_ref, depth| {
+            if let NodeBox::Leaf(_) = *node_ref.borrow() {
+                if report.histogram.len() <= depth {
+                    report.histogram.resize(depth + 1, 0);
+                }
+                report.histogram[depth] += 1;
+                report.leaves += 1;
+                total_depth += depth;
+            }
+            Ok(true)
+        })?;
+
+        if report.leaves == 0 {
+            return Ok(report);
+        }
+
+        report.min_depth = report.histogram.iter().position(|&n| n > 0).unwrap();
+        report.max_depth = report.histogram.len() - 1;
+        report.mean_depth = total_depth as f64 / report.leaves as f64;
+
+        let balanced_depth = report.leaves.next_power_of_two().trailing_zeros() as usize;
+        report.skewed = report.max_depth > BALANCE_SKEW_FACTOR * balanced_depth.max(1);
+
+        Ok(report)
+    }
+
+    /// Count the leaves under each key prefix of `depth` nibbles with a single traversal.
+    ///
+    /// Keys shorter than `depth` nibbles are counted under all of their nibbles. Only
+    /// non-empty prefixes are returned, in ascending order.
+    pub fn nibble_histogram(&self, depth: usize) -> Result<Vec<(NibblePrefix, usize)>> {
+        let mut counts: BTreeMap<NibblePrefix, usize> = BTreeMap::new();
+
+        self.walk_nodes(|_, node_ref, _| {
+            if let NodeBox::Leaf(ref n) = *node_ref.borrow() {
+                let prefix = n
+                    .key
+                    .iter()
+                    .flat_map(|b| [b >> 4, b & 0x0f])
+                    .take(depth)
+                    .collect();
+                *counts.entry(prefix).or_default() += 1;
+            }
+            Ok(true)
+        })?;
+
+        Ok(counts.into_iter().collect())
+    }
+
+    /// Write the encoded size of all nodes, grouped by key prefix, in folded stack format.
+    ///
+    /// Each line consists of the frames `mkvs;<byte>;<byte>;...` (the bytes of the key
+    /// prefix in hex) followed by the total size in bytes of the nodes under that prefix
+    /// which are not attributed to a longer prefix. Nodes are attributed to the full bytes of
+    /// the key prefix at their position, limited to `max_depth` bytes. The output can be
+    /// rendered as a flame graph.
+    pub fn storage_profile<W: Write>(&self, max_depth: usize, mut writer: W) -> Result<()> {
+        let mut sizes: BTreeMap<Vec<u8>, usize> = BTreeMap::new();
+        // Key prefix and its bit length for each internal node on the current path.
+        let mut path: Vec<(Key, Depth)> = Vec::new();
+
+        self.walk_nodes(|_, node_ref, depth| {
+            path.truncate(depth);
+            let (parent, parent_bits) = path.last().cloned().unwrap_or_default();
+            let node = node_ref.borrow();
+            let prefix = match *node {
+                NodeBox::Internal(ref n) => {
+                    let bits = parent_bits + n.label_bit_length;
+                    let key = parent.merge(parent_bits, &n.label, n.label_bit_length);
+                    let full_bytes = (bits / 8) as usize;
+                    let prefix = key[..full_bytes.min(max_depth)].to_vec();
+                    path.push((key, bits));
+                    prefix
+                }
+                NodeBox::Leaf(ref n) => n.key[..n.key.len().min(max_depth)].to_vec(),
+            };
+            *sizes.entry(prefix).or_default() += node.marshal_binary()?.len();
+            Ok(true)
+        })?;
+
+        for (prefix, size) in sizes {
+            let mut line = String::from("mkvs");
+            for byte in prefix {
+                line.push_str(&format!(";{:02x}", byte));
+            }
+            writeln!(writer, "{} {}", line, size)?;
+        }
+        Ok(())
+    }
+}
+
+/// Compute the difference in storage footprint between the trees at two roots, fetching
+/// nodes via the given read syncer.
+///
+/// Both trees are walked together and subtrees with the same hash on both sides are skipped,
+/// so the work is proportional to the size of the difference. In case the roots are
+/// unrelated, all nodes of `to` are reported as added and all nodes of `from` as removed.
+pub fn storage_delta(read_syncer: Box<dyn ReadSync>, from: Root, to: Root) -> Result<StorageDelta> {
+    let read_syncer = SharedReadSyncer(Rc::new(RefCell::new(read_syncer)));
+    let build_tree = |root| {
+        Tree::builder()
+            .with_capacity(0, 0)
+            .with_root(root)
+            .build(Box::new(read_syncer.clone()))
+    };
+    let from_tree = build_tree(from);
+    let to_tree = build_tree(to);
+
+    let mut walker = DeltaWalker {
+        from: &from_tree,
+        to: &to_tree,
+        delta: StorageDelta::default(),
+    };
+    let from_root = from_tree.cache.borrow().get_pending_root();
+    let to_root = to_tree.cache.borrow().get_pending_root();
+    walker.diff(Slot::root(from_root), Slot::root(to_root))?;
+    Ok(walker.delta)
+}
+
+/// A read syncer shared by multiple trees.
+#[derive(Clone)]
+struct SharedReadSyncer(Rc<RefCell<Box<dyn ReadSync>>>);
+
+impl ReadSync for SharedReadSyncer {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn sync_get(&mut self, request: GetRequest) -> Result<ProofResponse> {
+        self.0.borrow_mut().sync_get(request)
+    }
+
+    fn sync_get_prefixes(&mut self, request: GetPrefixesRequest) -> Result<ProofResponse> {
+        self.0.borrow_mut().sync_get_prefixes(request)
+    }
+
+    fn sync_iterate(&mut self, request: IterateRequest) -> Result<ProofResponse> {
+        self.0.borrow_mut().sync_iterate(request)
+    }
+}
+
+/// A pointer together with its position in the tree, as used by `Tree::walk_nodes`.
+struct Slot {
+    ptr: NodePtrRef,
+    /// Bit depth at which the label of the node starts.
+    bit_depth: Depth,
+    /// Key prefix at the position of the pointer and its bit length.
+    path: Key,
+    path_bits: Depth,
+}
+
+impl Slot {
+    fn root(ptr: NodePtrRef) -> Self {
+        Self {
+            ptr,
+            bit_depth: 0,
+            path: Key::new(),
+            path_bits: 0,
+        }
+    }
+}
+
+/// A dereferenced node together with its slot and the key prefix shared by all keys below
+/// it, i.e. the end of its label for internal nodes and the key for leaf nodes.
+struct SlotNode {
+    slot: Slot,
+    node_ref: NodeRef,
+    prefix: Key,
+    prefix_bits: Depth,
+}
+
+impl SlotNode {
+    fn is_leaf(&self) -> bool {
+        matches!(*self.node_ref.borrow(), NodeBox::Leaf(_))
+    }
+
+    fn size(&self) -> Result<usize> {
+        Ok(self.node_ref.borrow().marshal_binary()?.len())
+    }
+
+    /// Return the slots of the leaf node, left and right children of an internal node.
+    fn children(&self) -> Vec<Slot> {
+        match *self.node_ref.borrow() {
+            NodeBox::Internal(ref n) => vec![
+                Slot {
+                    ptr: n.leaf_node.clone(),
+                    bit_depth: self.prefix_bits,
+                    path: self.prefix.clone(),
+                    path_bits: self.prefix_bits,
+                },
+                Slot {
+                    ptr: n.left.clone(),
+                    bit_depth: self.prefix_bits,
+                    path: self.prefix.append_bit(self.prefix_bits, false),
+                    path_bits: self.prefix_bits + 1,
+                },
+                Slot {
+                    ptr: n.right.clone(),
+                    bit_depth: self.prefix_bits,
+                    path: self.prefix.append_bit(self.prefix_bits, true),
+                    path_bits: self.prefix_bits + 1,
+                },
+            ],
+            NodeBox::Leaf(_) => vec![],
+        }
+    }
+
+    /// Whether the prefix of this node is a prefix of the prefix of the other node.
+    fn has_prefix_of(&self, other: &SlotNode) -> bool {
+        self.prefix_bits <= other.prefix_bits
+            && self
+                .prefix
+                .common_prefix_len(self.prefix_bits, &other.prefix, other.prefix_bits)
+                >= self.prefix_bits
+    }
+
+    /// Return the index of the child of an internal node whose slot contains the other
+    /// node, which must have a longer prefix.
+    fn child_towards(&self, other: &SlotNode) -> usize {
+        if other.prefix.get_bit(self.prefix_bits) {
+            2
+        } else {
+            1
+        }
+    }
+}
+
+struct DeltaWalker<'a> {
+    from: &'a Tree,
+    to: &'a Tree,
+    delta: StorageDelta,
+}
+
+impl<'a> DeltaWalker<'a> {
+    fn deref(tree: &Tree, slot: Slot) -> Result<Option<SlotNode>> {
+        let node_ref = match tree.cache.borrow_mut().deref_node_ptr(
+            slot.ptr.clone(),
+            Some(FetcherSyncIterate::new(&slot.path, 0)),
+        )? {
+            Some(node_ref) => node_ref,
+            None => return Ok(None),
+        };
+
+        let (prefix, prefix_bits) = match *node_ref.borrow() {
+            NodeBox::Internal(ref n) => (
+                slot.path
+                    .merge(slot.bit_depth, &n.label, n.label_bit_length),
+                slot.bit_depth + n.label_bit_length,
+            ),
+            NodeBox::Leaf(ref n) => (n.key.clone(), n.key.bit_length()),
+        };
+        Ok(Some(SlotNode {
+            slot,
+            node_ref,
+            prefix,
+            prefix_bits,
+        }))
+    }
+
+    fn add(&mut self, node: &SlotNode) -> Result<()> {
+        self.delta.added_nodes += 1;
+        self.delta.added_bytes += node.size()?;
+        Ok(())
+    }
+
+    fn remove(&mut self, node: &SlotNode) -> Result<()> {
+        self.delta.removed_nodes += 1;
+        self.delta.removed_bytes += node.size()?;
+        Ok(())
+    }
+
+    /// Count all nodes of a subtree which is only present in one of the trees.
+    fn count_all(&mut self, tree: &Tree, slot: Slot, added: bool) -> Result<()> {
+        let delta = &mut self.delta;
+        tree._walk_nodes(
+            slot.ptr,
+            slot.bit_depth,
+            0,
+            (slot.path, slot.path_bits),
+            &mut |_, node_ref, _| {
+                let size = node_ref.borrow().marshal_binary()?.len();
+                if added {
+                    delta.added_nodes += 1;
+                    delta.added_bytes += size;
+                } else {
+                    delta.removed_nodes += 1;
+                    delta.removed_bytes += size;
+                }
+                Ok(true)
+            },
+            &mut |_, _, _, err| Err(err),
+        )
+    }
+
+    /// Compare the subtree at slot `a` of the old tree with the subtree at slot `b` of the
+    /// new tree, where all keys of one of the subtrees are within the other slot.
+    fn diff(&mut self, a: Slot, b: Slot) -> Result<()> {
+        if a.ptr.borrow().hash == b.ptr.borrow().hash {
+            // Shared subtree (or both empty).
+            return Ok(());
+        }
+
+        let (a, b) = match (Self::deref(self.from, a)?, Self::deref(self.to, b)?) {
+            (None, None) => return Ok(()),
+            (Some(a), None) => return self.count_all(self.from, a.slot, false),
+            (None, Some(b)) => return self.count_all(self.to, b.slot, true),
+            (Some(a), Some(b)) => (a, b),
+        };
+
+        if a.has_prefix_of(&b) && b.has_prefix_of(&a) {
+            // Both nodes are at the same position, so their children can be compared
+            // pairwise. A leaf can only correspond to the leaf node of an internal node.
+            match (a.is_leaf(), b.is_leaf()) {
+                (true, true) => {
+                    self.remove(&a)?;
+                    self.add(&b)
+                }
+                (false, false) => {
+                    self.remove(&a)?;
+                    self.add(&b)?;
+                    for (a_child, b_child) in a.children().into_iter().zip(b.children()) {
+                        self.diff(a_child, b_child)?;
+                    }
+                    Ok(())
+                }
+                (true, false) => {
+                    self.add(&b)?;
+                    let mut children = b.children().into_iter();
+                    self.diff(a.slot, children.next().expect("internal node"))?;
+                    for child in children {
+                        self.count_all(self.to, child, true)?;
+                    }
+                    Ok(())
+                }
+                (false, true) => {
+                    self.remove(&a)?;
+                    let mut children = a.children().into_iter();
+                    self.diff(children.next().expect("internal node"), b.slot)?;
+                    for child in children {
+                        self.count_all(self.from, child, false)?;
+                    }
+                    Ok(())
+                }
+            }
+        } else if !a.is_leaf() && a.has_prefix_of(&b) {
+            // All keys of the new subtree are within one child of the old node.
+            self.remove(&a)?;
+            let towards = a.child_towards(&b);
+            let mut b_slot = Some(b.slot);
+            for (i, child) in a.children().into_iter().enumerate() {
+                match b_slot.take() {
+                    Some(slot) if i == towards => self.diff(child, slot)?,
+                    slot => {
+                        b_slot = slot;
+                        self.count_all(self.from, child, false)?;
+                    }
+                }
+            }
+            Ok(())
+        } else if !b.is_leaf() && b.has_prefix_of(&a) {
+            // All keys of the old subtree are within one child of the new node.
+            self.add(&b)?;
+            let towards = b.child_towards(&a);
+            let mut a_slot = Some(a.slot);
+            for (i, child) in b.children().into_iter().enumerate() {
+                match a_slot.take() {
+                    Some(slot) if i == towards => self.diff(slot, child)?,
+                    slot => {
+                        a_slot = slot;
+                        self.count_all(self.to, child, true)?;
+                    }
+                }
+            }
+            Ok(())
+        } else {
+            // The subtrees have no keys in common, so nothing can be shared.
+            self.count_all(self.from, a.slot, false)?;
+            self.count_all(self.to, b.slot, true)
+        }
+    }
+}
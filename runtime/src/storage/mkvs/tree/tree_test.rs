@@ -1,10 +1,27 @@
-use std::{collections::HashSet, fs::File, io::BufReader, iter, iter::FromIterator, path::Path};
+use std::{
+    any::Any,
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    convert::TryInto,
+    fs::File,
+    io::BufReader,
+    iter,
+    iter::FromIterator,
+    path::Path,
+    rc::Rc,
+};
 
-use crate::storage::mkvs::{
-    interop::{Driver, ProtocolServer},
-    tests,
-    tree::*,
-    Iterator, LogEntry, LogEntryKind, WriteLog, MKVS,
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+use crate::{
+    common::crypto::hash::Hash,
+    storage::mkvs::{
+        interop::{Driver, ProtocolServer},
+        marshal::Marshal,
+        tests,
+        tree::*,
+        Iterator, LogEntry, LogEntryKind, WriteLog, MKVS,
+    },
 };
 
 const INSERT_ITEMS: usize = 1000;
@@ -379,6 +396,50 @@ fn test_insert_commit_each() {
     assert_eq!(format!("{:?}", hash), ALL_ITEMS_ROOT);
 }
 
+#[test]
+fn test_empty_commit() {
+    let mut tree = Tree::builder()
+        .with_root_type(RootType::State)
+        .build(Box::new(NoopReadSyncer));
+
+    // Committing an empty tree yields the empty root.
+    let hash = Tree::commit(&mut tree, Default::default(), 0).expect("commit");
+    assert_eq!(hash, Hash::empty_hash());
+
+    let (keys, values) = generate_key_value_pairs();
+    for (key, value) in keys.iter().zip(values.iter()) {
+        tree.insert(key, value).expect("insert");
+    }
+    let hash = Tree::commit(&mut tree, Default::default(), 1).expect("commit");
+    assert_eq!(format!("{:?}", hash), ALL_ITEMS_ROOT);
+
+    // Committing again without any updates must not change the root or the cache.
+    let stats = tree.cache.borrow().stats();
+    let pending_root = tree.cache.borrow().get_pending_root();
+    for version in 2..5 {
+        let new_hash = Tree::commit(&mut tree, Default::default(), version).expect("commit");
+        assert_eq!(new_hash, hash, "empty commit should not change the root");
+    }
+    let new_stats = tree.cache.borrow().stats();
+    assert_eq!(new_stats.internal_node_count, stats.internal_node_count);
+    assert_eq!(new_stats.leaf_value_size, stats.leaf_value_size);
+    assert!(Rc::ptr_eq(
+        &pending_root,
+        &tree.cache.borrow().get_pending_root()
+    ));
+
+    // Same for an overlay without any updates.
+    let mut overlay = OverlayTree::new(&mut tree);
+    let (write_log, new_hash) = overlay
+        .commit_both(Default::default(), 5)
+        .expect("commit_both");
+    assert!(
+        write_log.is_empty(),
+        "empty commit should have an empty write log"
+    );
+    assert_eq!(new_hash, hash);
+}
+
 #[test]
 fn test_remove() {
     let mut tree = Tree::builder()
@@ -452,6 +513,719 @@ fn test_remove() {
     assert_eq!(hash, Hash::empty_hash());
 }
 
+#[test]
+fn test_remove_collapse() {
+    let build_tree = |keys: &[&[u8]]| -> Tree {
+        let mut tree = Tree::builder()
+            .with_root_type(RootType::State)
+            .build(Box::new(NoopReadSyncer));
+        tree.insert(b"other", b"value").expect("insert");
+        for key in keys {
+            tree.insert(key, b"value").expect("insert");
+        }
+        tree.commit(Default::default(), 0).expect("commit");
+        tree
+    };
+    let check_canonical = |tree: &mut Tree, survivors: &[&[u8]]| {
+        let hash = tree.commit(Default::default(), 0).expect("commit");
+        let expected = build_tree(survivors).commit(Default::default(), 0).unwrap();
+        assert_eq!(hash, expected, "survivors: {:?}", survivors);
+
+        // No internal node may be left with a single child.
+        tree.walk_nodes(|_, node_ref, _| {
+            if let NodeBox::Internal(ref n) = *node_ref.borrow() {
+                let children = [&n.leaf_node, &n.left, &n.right]
+                    .iter()
+                    .filter(|child| !child.borrow().is_null())
+                    .count();
+                assert!(children >= 2, "internal node with {} children", children);
+            }
+            Ok(true)
+        })
+        .expect("walk_nodes");
+    };
+
+    let cases: &[[&[u8]; 3]] = &[
+        // Keys branching below a shared prefix.
+        [b"prefix/a", b"prefix/b", b"prefix/c"],
+        // One key is a prefix of the others and ends up in a leaf node of an internal node.
+        [b"foo", b"foo/bar", b"foo/baz"],
+        // Keys branching at different depths.
+        [b"k\x00", b"k\x01", b"k\x80"],
+    ];
+    let orders = [
+        [0, 1, 2],
+        [0, 2, 1],
+        [1, 0, 2],
+        [1, 2, 0],
+        [2, 0, 1],
+        [2, 1, 0],
+    ];
+    for keys in cases {
+        for order in &orders {
+            // Removals committed one at a time.
+            let mut tree = build_tree(keys);
+            tree.remove(keys[order[0]]).expect("remove");
+            check_canonical(&mut tree, &[keys[order[1]], keys[order[2]]]);
+            tree.remove(keys[order[1]]).expect("remove");
+            check_canonical(&mut tree, &[keys[order[2]]]);
+
+            // Removals committed together.
+            let mut tree = build_tree(keys);
+            tree.remove(keys[order[0]]).expect("remove");
+            tree.remove(keys[order[1]]).expect("remove");
+            check_canonical(&mut tree, &[keys[order[2]]]);
+
+            // Removing the last key leaves only the unrelated key.
+            tree.remove(keys[order[2]]).expect("remove");
+            check_canonical(&mut tree, &[]);
+        }
+    }
+}
+
+#[test]
+fn test_get_map() {
+    let mut tree = Tree::builder()
+        .with_root_type(RootType::State)
+        .build(Box::new(NoopReadSyncer));
+
+    let (keys, values) = generate_key_value_pairs();
+    for (key, value) in keys.iter().zip(values.iter()) {
+        tree.insert(key, value).expect("insert");
+    }
+    tree.commit(Default::default(), 0).expect("commit");
+
+    // Uncommitted updates must be visible.
+    tree.insert(&keys[1], b"updated").expect("insert");
+    tree.remove(&keys[2]).expect("remove");
+
+    let map = tree
+        .get_map(&[&keys[0], &keys[1], &keys[2], b"missing", &keys[0]])
+        .expect("get_map");
+    assert_eq!(map.len(), 2);
+    assert_eq!(map.get(&keys[0]), Some(&values[0]));
+    assert_eq!(map.get(&keys[1]), Some(&b"updated".to_vec()));
+    assert!(!map.contains_key(&keys[2]));
+    assert!(!map.contains_key(&b"missing"[..]));
+
+    let map = tree.get_map(&[]).expect("get_map");
+    assert!(map.is_empty());
+}
+
+#[test]
+fn test_get_with_path_hashes() {
+    let mut tree = Tree::builder()
+        .with_root_type(RootType::State)
+        .build(Box::new(NoopReadSyncer));
+
+    let (keys, values) = generate_key_value_pairs();
+    for (key, value) in keys.iter().zip(values.iter()) {
+        tree.insert(key, value).expect("insert");
+    }
+    let root_hash = tree.commit(Default::default(), 0).expect("commit");
+
+    // Collect the expected path to each leaf by walking the whole tree.
+    let mut expected = HashMap::new();
+    let mut stack: Vec<Hash> = Vec::new();
+    tree.walk_nodes(|_, node_ref, depth| {
+        stack.truncate(depth);
+        stack.push(node_ref.borrow().get_hash());
+        if let NodeBox::Leaf(ref n) = *node_ref.borrow() {
+            expected.insert(n.key.clone(), stack.clone());
+        }
+        Ok(true)
+    })
+    .expect("walk_nodes");
+    assert_eq!(expected.len(), keys.len());
+
+    for (key, value) in keys.iter().zip(values.iter()) {
+        let (result, path) = tree
+            .get_with_path_hashes(key)
+            .expect("get_with_path_hashes");
+        assert_eq!(result.as_ref(), Some(value));
+        assert_eq!(path[0], root_hash);
+        assert_eq!(&path, expected.get(key).unwrap());
+    }
+
+    // Paths of missing keys end where the lookup ended.
+    let (result, path) = tree
+        .get_with_path_hashes(b"missing")
+        .expect("get_with_path_hashes");
+    assert_eq!(result, None);
+    assert!(!path.is_empty());
+    assert_eq!(path[0], root_hash);
+
+    // An empty tree has no nodes to traverse.
+    let tree = Tree::builder()
+        .with_root_type(RootType::State)
+        .build(Box::new(NoopReadSyncer));
+    let (result, path) = tree
+        .get_with_path_hashes(b"foo")
+        .expect("get_with_path_hashes");
+    assert_eq!(result, None);
+    assert!(path.is_empty());
+}
+
+#[test]
+fn test_get_observed() {
+    // A root internal node with the two keys as leaves.
+    let mut tree = Tree::builder()
+        .with_root_type(RootType::State)
+        .build(Box::new(NoopReadSyncer));
+    tree.insert(b"foo", b"bar").expect("insert");
+    tree.insert(b"moo", b"boo").expect("insert");
+
+    let observed = |tree: &Tree, key: &[u8]| tree.get_observed(key).expect("get_observed");
+    let local = |depth, uncommitted| ReadObservation {
+        depth,
+        cache_hits: depth,
+        uncommitted,
+        ..Default::default()
+    };
+
+    assert_eq!(
+        observed(&tree, b"foo"),
+        (Some(b"bar".to_vec()), local(2, true))
+    );
+    let hash = tree.commit(Default::default(), 0).expect("commit");
+    assert_eq!(
+        observed(&tree, b"foo"),
+        (Some(b"bar".to_vec()), local(2, false))
+    );
+    // The lookup for a missing key ends at the leaf with a different key.
+    assert_eq!(observed(&tree, b"zoo"), (None, local(2, false)));
+
+    // A single fetch returns the root together with both leaves.
+    let remote_tree = Tree::builder()
+        .with_root(Root {
+            root_type: RootType::State,
+            hash,
+            ..Default::default()
+        })
+        .build(Box::new(LocalReadSyncer::new(
+            &tree,
+            Rc::new(RefCell::new(Vec::new())),
+        )));
+    let root = tree.cache.borrow().get_pending_root().borrow().get_node();
+    let mut builder = ProofBuilder::new(hash);
+    builder.include(&root.borrow());
+    if let NodeBox::Internal(ref n) = *root.borrow() {
+        builder.include(&n.left.borrow().get_node().borrow());
+        builder.include(&n.right.borrow().get_node().borrow());
+    }
+    let fetched_bytes = builder
+        .build()
+        .entries
+        .iter()
+        .flatten()
+        .map(|entry| entry.len())
+        .sum();
+
+    assert_eq!(
+        observed(&remote_tree, b"moo"),
+        (
+            Some(b"boo".to_vec()),
+            ReadObservation {
+                depth: 2,
+                fetches: 1,
+                fetched_bytes,
+                cache_hits: 1,
+                uncommitted: false,
+            }
+        )
+    );
+    assert_eq!(
+        observed(&remote_tree, b"moo"),
+        (Some(b"boo".to_vec()), local(2, false))
+    );
+}
+
+#[test]
+fn test_get_many() {
+    let mut tree = Tree::builder()
+        .with_root_type(RootType::State)
+        .build(Box::new(NoopReadSyncer));
+    let (other_keys, other_values) = generate_key_value_pairs_ex("other ".to_string(), 1000);
+    let (keys, values) = generate_key_value_pairs_ex("prefix/".to_string(), 200);
+    for (key, value) in other_keys
+        .iter()
+        .zip(&other_values)
+        .chain(keys.iter().zip(&values))
+    {
+        tree.insert(key, value).expect("insert");
+    }
+    let hash = tree.commit(Default::default(), 0).expect("commit");
+
+    // Each node on the paths to the requested keys is dereferenced once.
+    let query: Vec<&[u8]> = keys.iter().map(|key| key.as_slice()).collect();
+    let mut distinct = HashSet::new();
+    let mut path_lengths = 0;
+    for key in &query {
+        let (_, path) = tree
+            .get_with_path_hashes(key)
+            .expect("get_with_path_hashes");
+        path_lengths += path.len();
+        distinct.extend(path);
+    }
+
+    let remote_tree = Tree::builder()
+        .with_root(Root {
+            root_type: RootType::State,
+            hash,
+            ..Default::default()
+        })
+        .build(Box::new(LocalReadSyncer::new(
+            &tree,
+            Rc::new(RefCell::new(Vec::new())),
+        )));
+    let counters = |tree: &Tree| tree.cache.borrow().fetch_counters();
+
+    let before = counters(&remote_tree);
+    let cold = remote_tree.get_many(&query).expect("get_many");
+    let after = counters(&remote_tree);
+    assert_eq!(cold, values.iter().cloned().map(Some).collect::<Vec<_>>());
+    assert!(after.fetches - before.fetches <= distinct.len());
+
+    let before = counters(&remote_tree);
+    remote_tree.get_many(&query).expect("get_many");
+    let after = counters(&remote_tree);
+    assert_eq!(after.fetches, before.fetches);
+    assert_eq!(after.cache_hits - before.cache_hits, distinct.len());
+    assert!(distinct.len() < path_lengths / 2);
+
+    // Uncommitted changes are visible as well.
+    tree.insert(b"prefix/uncommitted", b"value")
+        .expect("insert");
+    tree.remove(&keys[0]).expect("remove");
+
+    // Results match looped gets, in the original order, including missing keys, keys which
+    // are prefixes of other keys and duplicates.
+    let mut candidates: Vec<Vec<u8>> = keys.iter().chain(&other_keys).cloned().collect();
+    candidates.extend(vec![
+        b"prefix/uncommitted".to_vec(),
+        b"prefix/".to_vec(),
+        b"prefix/missing".to_vec(),
+        b"".to_vec(),
+        b"zzz".to_vec(),
+    ]);
+    let mut rng = StdRng::seed_from_u64(42);
+    for _ in 0..50 {
+        let count = rng.gen_range(0..100);
+        let query: Vec<&[u8]> = (0..count)
+            .map(|_| candidates.choose(&mut rng).unwrap().as_slice())
+            .collect();
+        let expected: Vec<Option<Vec<u8>>> = query
+            .iter()
+            .map(|key| tree.get(key).expect("get"))
+            .collect();
+        assert_eq!(tree.get_many(&query).expect("get_many"), expected);
+    }
+}
+
+#[test]
+fn test_get_with_proof() {
+    let mut tree = Tree::builder()
+        .with_root_type(RootType::State)
+        .build(Box::new(NoopReadSyncer));
+
+    let (keys, values) = generate_key_value_pairs();
+    for (key, value) in keys.iter().zip(values.iter()) {
+        tree.insert(key, value).expect("insert");
+    }
+    let hash = tree.commit(Default::default(), 0).expect("commit");
+
+    let remote_tree = |positions: Rc<RefCell<Vec<Hash>>>| {
+        Tree::builder()
+            .with_root(Root {
+                root_type: RootType::State,
+                hash,
+                ..Default::default()
+            })
+            .build(Box::new(LocalReadSyncer::new(&tree, positions)))
+    };
+
+    for (key, value) in keys.iter().zip(values.iter()).step_by(97) {
+        // A plain lookup on a cold cache.
+        let get_positions = Rc::new(RefCell::new(Vec::new()));
+        remote_tree(get_positions.clone()).get(key).expect("get");
+
+        // The combined lookup fetches exactly the same nodes, each of them once.
+        let positions = Rc::new(RefCell::new(Vec::new()));
+        let remote_tree = remote_tree(positions.clone());
+        let (got_value, proof) = remote_tree
+            .get_with_proof(key)
+            .expect("get_with_proof")
+            .expect("key exists");
+        assert_eq!(&got_value, value);
+        assert_eq!(*positions.borrow(), *get_positions.borrow());
+        let unique: HashSet<Hash> = positions.borrow().iter().copied().collect();
+        assert_eq!(unique.len(), positions.borrow().len());
+
+        let (verified, _) = verify_pair_proof(hash, &proof, key, key).expect("verify");
+        assert_eq!(verified.as_ref(), Some(value));
+        assert_eq!(
+            Some(proof),
+            tree.get_proof(key).expect("get_proof"),
+            "proof should match get_proof"
+        );
+
+        // Both the value and the proof are now cached.
+        let fetches = positions.borrow().len();
+        remote_tree.get(key).expect("get");
+        remote_tree.get_proof(key).expect("get_proof");
+        assert_eq!(positions.borrow().len(), fetches);
+    }
+
+    assert!(tree
+        .get_with_proof(b"missing")
+        .expect("get_with_proof")
+        .is_none());
+}
+
+#[test]
+fn test_prove_pair() {
+    let mut tree = Tree::builder()
+        .with_root_type(RootType::State)
+        .build(Box::new(NoopReadSyncer));
+
+    let (keys, values) = generate_key_value_pairs();
+    for (key, value) in keys.iter().zip(values.iter()) {
+        tree.insert(key, value).expect("insert");
+    }
+    let shared_a = b"a long shared prefix of two keys, a".to_vec();
+    let shared_b = b"a long shared prefix of two keys, b".to_vec();
+    let first_bit = vec![0xff, 0x01];
+    for key in &[
+        &shared_a,
+        &shared_b,
+        &first_bit,
+        &b"foo".to_vec(),
+        &b"foo/bar".to_vec(),
+    ] {
+        tree.insert(key, b"value").expect("insert");
+    }
+    let root = tree.commit(Default::default(), 0).expect("commit");
+
+    let check_pair = |key_a: &[u8], key_b: &[u8]| -> Proof {
+        let proof = tree.prove_pair(key_a, key_b).expect("prove_pair");
+        let (value_a, value_b) =
+            verify_pair_proof(root, &proof, key_a, key_b).expect("verify_pair_proof");
+        assert_eq!(value_a, tree.get(key_a).expect("get"));
+        assert_eq!(value_b, tree.get(key_b).expect("get"));
+        proof
+    };
+
+    // Keys sharing most of their path result in a smaller proof than separate proofs.
+    let proof = check_pair(&shared_a, &shared_b);
+    let separate = tree.get_proof(&shared_a).unwrap().unwrap().entries.len()
+        + tree.get_proof(&shared_b).unwrap().unwrap().entries.len();
+    assert!(proof.entries.len() < separate);
+
+    // Keys diverging at the first bit.
+    check_pair(&keys[0], &first_bit);
+    // One key is a prefix of the other.
+    check_pair(b"foo", b"foo/bar");
+    check_pair(b"foo/bar", b"foo");
+    // Absent keys.
+    check_pair(&keys[1], b"missing");
+    check_pair(b"fo", b"foo/ba");
+    // Same key twice.
+    check_pair(&keys[2], &keys[2]);
+
+    // The proof must not verify against a different root or for keys it does not cover.
+    let proof = tree.prove_pair(&keys[0], &first_bit).expect("prove_pair");
+    assert!(verify_pair_proof(Hash::empty_hash(), &proof, &keys[0], &first_bit).is_err());
+    assert!(verify_pair_proof(root, &proof, &keys[0], &keys[500]).is_err());
+
+    // Empty tree.
+    let mut tree = Tree::builder()
+        .with_root_type(RootType::State)
+        .build(Box::new(NoopReadSyncer));
+    tree.commit(Default::default(), 0).expect("commit");
+    let proof = tree.prove_pair(b"foo", b"bar").expect("prove_pair");
+    let values =
+        verify_pair_proof(Hash::empty_hash(), &proof, b"foo", b"bar").expect("verify_pair_proof");
+    assert_eq!(values, (None, None));
+}
+
+#[test]
+fn test_retain() {
+    let mut tree = Tree::builder()
+        .with_root_type(RootType::State)
+        .build(Box::new(NoopReadSyncer));
+
+    let value_of = |i: u32| i.to_be_bytes().to_vec();
+    for i in 0..100u32 {
+        tree.insert(format!("key {}", i).as_bytes(), &value_of(i))
+            .expect("insert");
+    }
+    tree.commit(Default::default(), 0).expect("commit");
+
+    // Uncommitted updates must be considered as well.
+    tree.insert(b"key 3", &value_of(300)).expect("insert");
+    tree.insert(b"key 100", &value_of(100)).expect("insert");
+    tree.remove(b"key 4").expect("remove");
+
+    let is_even = |value: &[u8]| u32::from_be_bytes(value.try_into().unwrap()) % 2 == 0;
+    let removed = tree.retain(|_, value| is_even(value)).expect("retain");
+    assert_eq!(removed, 49);
+
+    let mut it = tree.iter();
+    it.rewind();
+    let survivors: Vec<Vec<u8>> = it.map(|(key, _)| key).collect();
+    let mut expected: Vec<Vec<u8>> = (0..=100u32)
+        .filter(|i| (i % 2 == 0 && *i != 4) || *i == 3)
+        .map(|i| format!("key {}", i).into_bytes())
+        .collect();
+    expected.sort();
+    assert_eq!(survivors, expected);
+
+    let removed = tree.retain(|_, _| true).expect("retain");
+    assert_eq!(removed, 0);
+}
+
+#[test]
+fn test_rename() {
+    let mut tree = Tree::builder()
+        .with_root_type(RootType::State)
+        .build(Box::new(NoopReadSyncer));
+
+    tree.insert(b"foo", b"bar").expect("insert");
+    tree.insert(b"moo", b"boo").expect("insert");
+    tree.commit(Default::default(), 0).expect("commit");
+
+    // Rename a present key.
+    assert!(tree.rename(b"foo", b"goo").expect("rename"));
+    assert_eq!(tree.get(b"foo").expect("get"), None);
+    assert_eq!(tree.get(b"goo").expect("get"), Some(b"bar".to_vec()));
+
+    // Rename an absent key.
+    let hash = tree.commit(Default::default(), 1).expect("commit");
+    assert!(!tree.rename(b"foo", b"moo").expect("rename"));
+    assert_eq!(tree.get(b"moo").expect("get"), Some(b"boo".to_vec()));
+    assert_eq!(tree.commit(Default::default(), 2).expect("commit"), hash);
+
+    // Rename onto an existing key.
+    assert!(tree.rename(b"goo", b"moo").expect("rename"));
+    assert_eq!(tree.get(b"goo").expect("get"), None);
+    assert_eq!(tree.get(b"moo").expect("get"), Some(b"bar".to_vec()));
+
+    // Rename onto itself.
+    assert!(tree.rename(b"moo", b"moo").expect("rename"));
+    assert_eq!(tree.get(b"moo").expect("get"), Some(b"bar".to_vec()));
+
+    // The result matches a tree built directly.
+    let hash = tree.commit(Default::default(), 3).expect("commit");
+    let mut expected = Tree::builder()
+        .with_root_type(RootType::State)
+        .build(Box::new(NoopReadSyncer));
+    expected.insert(b"moo", b"bar").expect("insert");
+    assert_eq!(
+        expected.commit(Default::default(), 0).expect("commit"),
+        hash
+    );
+}
+
+#[test]
+fn test_rename_failure() {
+    let mut tree = Tree::builder()
+        .with_capacity(128, 0)
+        .with_root_type(RootType::State)
+        .build(Box::new(NoopReadSyncer));
+
+    let (keys, values) = generate_key_value_pairs_ex("".to_string(), 300);
+    for (key, value) in keys.iter().zip(values.iter()) {
+        tree.insert(key, value).expect("insert");
+    }
+    Tree::commit(&mut tree, Default::default(), 0).expect("commit");
+
+    // Evicted nodes cannot be fetched via the no-op read syncer, so renaming a cached key
+    // onto a key in an evicted subtree fails.
+    let (from, value) = keys
+        .iter()
+        .zip(values.iter())
+        .find(|(key, _)| tree.get(key).is_ok())
+        .expect("some key should be cached");
+    let to = keys
+        .iter()
+        .find(|key| tree.get(key).is_err())
+        .expect("some key should be evicted");
+
+    assert!(tree.rename(from, to).is_err());
+    assert_eq!(tree.get(from).expect("get"), Some(value.clone()));
+}
+
+#[test]
+fn test_get_or_populate() {
+    let mut tree = Tree::builder()
+        .with_root_type(RootType::State)
+        .build(Box::new(NoopReadSyncer));
+    tree.insert(b"foo", b"bar").expect("insert");
+    tree.commit(Default::default(), 0).expect("commit");
+
+    let loads = RefCell::new(Vec::new());
+    let loader = |key: &[u8]| {
+        loads.borrow_mut().push(key.to_vec());
+        if key == b"missing" {
+            None
+        } else {
+            Some(b"loaded".to_vec())
+        }
+    };
+
+    // Existing keys do not invoke the loader.
+    assert_eq!(
+        tree.get_or_populate(b"foo", loader)
+            .expect("get_or_populate"),
+        Some(b"bar".to_vec())
+    );
+    assert!(loads.borrow().is_empty());
+
+    // A miss invokes the loader once and inserts the loaded value.
+    assert_eq!(
+        tree.get_or_populate(b"moo", loader)
+            .expect("get_or_populate"),
+        Some(b"loaded".to_vec())
+    );
+    assert_eq!(*loads.borrow(), vec![b"moo".to_vec()]);
+    assert_eq!(
+        tree.get_or_populate(b"moo", loader)
+            .expect("get_or_populate"),
+        Some(b"loaded".to_vec())
+    );
+    assert_eq!(tree.get(b"moo").expect("get"), Some(b"loaded".to_vec()));
+    assert_eq!(loads.borrow().len(), 1);
+
+    // Nothing is inserted when the loader has no value.
+    assert_eq!(
+        tree.get_or_populate(b"missing", loader)
+            .expect("get_or_populate"),
+        None
+    );
+    assert_eq!(loads.borrow().len(), 2);
+    assert_eq!(tree.get(b"missing").expect("get"), None);
+
+    // Populated values are committed like any other insert.
+    let hash = tree.commit(Default::default(), 1).expect("commit");
+    let mut expected = Tree::builder()
+        .with_root_type(RootType::State)
+        .build(Box::new(NoopReadSyncer));
+    expected.insert(b"foo", b"bar").expect("insert");
+    expected.insert(b"moo", b"loaded").expect("insert");
+    assert_eq!(
+        expected.commit(Default::default(), 0).expect("commit"),
+        hash
+    );
+}
+
+/// A read syncer serving nodes of a local tree, which answers each request with a proof
+/// containing only the requested node and its direct children.
+pub(super) struct LocalReadSyncer {
+    nodes: HashMap<Hash, NodeRef>,
+    positions: Rc<RefCell<Vec<Hash>>>,
+}
+
+impl LocalReadSyncer {
+    pub(super) fn new(tree: &Tree, positions: Rc<RefCell<Vec<Hash>>>) -> Self {
+        let mut nodes = HashMap::new();
+        tree.walk_nodes(|_, node_ref, _| {
+            nodes.insert(node_ref.borrow().get_hash(), node_ref.clone());
+            Ok(true)
+        })
+        .expect("walk_nodes");
+
+        Self { nodes, positions }
+    }
+
+    fn proof(&self, position: Hash) -> Result<ProofResponse> {
+        self.positions.borrow_mut().push(position);
+
+        let node_ref = self
+            .nodes
+            .get(&position)
+            .ok_or_else(|| anyhow::anyhow!("unknown node"))?;
+        let mut builder = ProofBuilder::new(position);
+        builder.include(&node_ref.borrow());
+        if let NodeBox::Internal(ref n) = *node_ref.borrow() {
+            for child in &[&n.leaf_node, &n.left, &n.right] {
+                if let Some(ref child_ref) = child.borrow().node {
+                    builder.include(&child_ref.borrow());
+                }
+            }
+        }
+
+        Ok(ProofResponse {
+            proof: builder.build(),
+        })
+    }
+}
+
+impl ReadSync for LocalReadSyncer {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn sync_get(&mut self, request: GetRequest) -> Result<ProofResponse> {
+        self.proof(request.tree.position)
+    }
+
+    fn sync_get_prefixes(&mut self, request: GetPrefixesRequest) -> Result<ProofResponse> {
+        self.proof(request.tree.position)
+    }
+
+    fn sync_iterate(&mut self, request: IterateRequest) -> Result<ProofResponse> {
+        self.proof(request.tree.position)
+    }
+}
+
+#[test]
+fn test_warm_top() {
+    let mut tree = Tree::builder()
+        .with_root_type(RootType::State)
+        .build(Box::new(NoopReadSyncer));
+
+    let (keys, values) = generate_key_value_pairs();
+    for (key, value) in keys.iter().zip(values.iter()) {
+        tree.insert(key, value).expect("insert");
+    }
+    let hash = tree.commit(Default::default(), 0).expect("commit");
+
+    // Depth of each node in the tree.
+    let mut depths = HashMap::new();
+    tree.walk_nodes(|_, node_ref, depth| {
+        depths.insert(node_ref.borrow().get_hash(), depth);
+        Ok(true)
+    })
+    .expect("walk_nodes");
+
+    let positions = Rc::new(RefCell::new(Vec::new()));
+    let remote_tree = Tree::builder()
+        .with_capacity(0, 0)
+        .with_root(Root {
+            root_type: RootType::State,
+            hash,
+            ..Default::default()
+        })
+        .build(Box::new(LocalReadSyncer::new(&tree, positions.clone())));
+
+    assert_eq!(remote_tree.warm_top(0).expect("warm_top"), 0);
+    assert!(positions.borrow().is_empty());
+
+    let loaded = remote_tree.warm_top(3).expect("warm_top");
+    assert_eq!(loaded, depths.values().filter(|depth| **depth < 3).count());
+    positions.borrow_mut().clear();
+
+    // Reads must only fetch nodes below the warmed levels.
+    for (key, value) in keys.iter().zip(values.iter()) {
+        assert_eq!(remote_tree.get(key).expect("get"), Some(value.clone()));
+    }
+    let positions = positions.borrow();
+    assert!(!positions.is_empty());
+    for position in positions.iter() {
+        assert!(depths[position] >= 3, "fetched node above warmed levels");
+    }
+}
+
 #[test]
 fn test_syncer_basic() {
     let server = ProtocolServer::new(None);
@@ -607,6 +1381,165 @@ fn test_syncer_insert() {
     assert_eq!(0, stats.sync_iterate_count, "sync_iterate count");
 }
 
+#[test]
+fn test_overlay_skip_unchanged() {
+    let mut tree = Tree::builder()
+        .with_root_type(RootType::State)
+        .with_hot_key_tracking(10)
+        .build(Box::new(NoopReadSyncer));
+    tree.insert(b"foo", b"bar").expect("insert");
+    tree.insert(b"moo", b"boo").expect("insert");
+    let hash = tree.commit(Default::default(), 0).expect("commit");
+
+    // By default, inserts of unchanged values are written.
+    let mut overlay = OverlayTree::new(&mut tree);
+    overlay.insert(b"foo", b"bar").expect("insert");
+    let (write_log, _) = overlay.commit_both(Default::default(), 1).expect("commit");
+    assert_eq!(write_log, vec![LogEntry::new(b"foo", b"bar")]);
+
+    let mut overlay = OverlayTree::new(&mut tree).with_skip_unchanged(true);
+    assert_eq!(
+        overlay.insert(b"foo", b"bar").expect("insert"),
+        Some(b"bar".to_vec())
+    );
+    // Changing a value and changing it back is also a no-op.
+    overlay.insert(b"moo", b"changed").expect("insert");
+    assert_eq!(
+        overlay.insert(b"moo", b"boo").expect("insert"),
+        Some(b"changed".to_vec())
+    );
+    overlay.remove(b"foo").expect("remove");
+    overlay.insert(b"foo", b"bar").expect("insert");
+    let (write_log, new_hash) = overlay.commit_both(Default::default(), 2).expect("commit");
+    assert!(write_log.is_empty(), "write log should be empty");
+    assert_eq!(new_hash, hash);
+    assert!(
+        tree.commit_stats()
+            .expect("commit stats")
+            .hot_keys
+            .is_empty(),
+        "no nodes should be rewritten"
+    );
+
+    // Changed values are still written.
+    let mut overlay = OverlayTree::new(&mut tree).with_skip_unchanged(true);
+    overlay.insert(b"foo", b"baz").expect("insert");
+    overlay.insert(b"new", b"value").expect("insert");
+    let (write_log, _) = overlay.commit_both(Default::default(), 3).expect("commit");
+    assert_eq!(
+        write_log,
+        vec![
+            LogEntry::new(b"foo", b"baz"),
+            LogEntry::new(b"new", b"value")
+        ]
+    );
+}
+
+#[test]
+fn test_overlay_apply_write_log_checked() {
+    let build_tree = || {
+        let mut tree = Tree::builder()
+            .with_root_type(RootType::State)
+            .build(Box::new(NoopReadSyncer));
+        tree.insert(b"a", b"base").expect("insert");
+        tree.insert(b"b", b"base").expect("insert");
+        tree.insert(b"c", b"base").expect("insert");
+        tree.commit(Default::default(), 0).expect("commit");
+        tree
+    };
+
+    let local_changes = |overlay: &mut OverlayTree<&mut Tree>| {
+        overlay.insert(b"a", b"local").expect("insert");
+        overlay.remove(b"b").expect("remove");
+        overlay.insert(b"d", b"local").expect("insert");
+    };
+    let write_log = vec![
+        LogEntry::new(b"a", b"log"),
+        LogEntry {
+            key: b"b".to_vec(),
+            value: None,
+        },
+        LogEntry::new(b"c", b"log"),
+        LogEntry {
+            key: b"d".to_vec(),
+            value: None,
+        },
+    ];
+    let expected_conflicts = vec![
+        WriteConflict {
+            key: b"a".to_vec(),
+            local: Some(b"local".to_vec()),
+            log: Some(b"log".to_vec()),
+        },
+        WriteConflict {
+            key: b"d".to_vec(),
+            local: Some(b"local".to_vec()),
+            log: None,
+        },
+    ];
+    let contents = |tree: &Tree| -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut it = tree.iter();
+        it.rewind();
+        it.collect()
+    };
+    let entry = |key: &[u8], value: &[u8]| (key.to_vec(), value.to_vec());
+
+    // Fail leaves the local changes untouched.
+    let mut tree = build_tree();
+    let mut overlay = OverlayTree::new(&mut tree);
+    local_changes(&mut overlay);
+    let err = overlay
+        .apply_write_log_checked(&write_log, ConflictPolicy::Fail)
+        .expect_err("apply should fail");
+    match err.downcast_ref::<TreeError>() {
+        Some(TreeError::WriteLogConflict(conflicts)) => assert_eq!(conflicts, &expected_conflicts),
+        _ => panic!("unexpected error: {}", err),
+    }
+    overlay.commit_both(Default::default(), 1).expect("commit");
+    assert_eq!(
+        contents(&tree),
+        vec![
+            entry(b"a", b"local"),
+            entry(b"c", b"base"),
+            entry(b"d", b"local")
+        ]
+    );
+
+    // PreferLocal keeps the local changes.
+    let mut tree = build_tree();
+    let mut overlay = OverlayTree::new(&mut tree);
+    local_changes(&mut overlay);
+    let report = overlay
+        .apply_write_log_checked(&write_log, ConflictPolicy::PreferLocal)
+        .expect("apply");
+    assert_eq!(report.applied, 2);
+    assert_eq!(report.conflicts, expected_conflicts);
+    overlay.commit_both(Default::default(), 1).expect("commit");
+    assert_eq!(
+        contents(&tree),
+        vec![
+            entry(b"a", b"local"),
+            entry(b"c", b"log"),
+            entry(b"d", b"local")
+        ]
+    );
+
+    // PreferLog overwrites the local changes.
+    let mut tree = build_tree();
+    let mut overlay = OverlayTree::new(&mut tree);
+    local_changes(&mut overlay);
+    let report = overlay
+        .apply_write_log_checked(&write_log, ConflictPolicy::PreferLog)
+        .expect("apply");
+    assert_eq!(report.applied, 4);
+    assert_eq!(report.conflicts, expected_conflicts);
+    overlay.commit_both(Default::default(), 1).expect("commit");
+    assert_eq!(
+        contents(&tree),
+        vec![entry(b"a", b"log"), entry(b"c", b"log")]
+    );
+}
+
 #[test]
 fn test_syncer_writelog_remove() {
     let server = ProtocolServer::new(None);
@@ -751,9 +1684,405 @@ fn test_node_eviction() {
     );
 }
 
+#[test]
+fn test_salvage() {
+    let mut tree = Tree::builder()
+        .with_capacity(128, 0)
+        .with_root_type(RootType::State)
+        .build(Box::new(NoopReadSyncer));
+
+    let (keys, values) = generate_key_value_pairs_ex("foo".to_string(), 300);
+    for (key, value) in keys.iter().zip(values.iter()) {
+        tree.insert(key, value).expect("insert");
+    }
+    Tree::commit(&mut tree, Default::default(), 0).expect("commit");
+
+    // Evicted nodes cannot be fetched via the no-op read syncer, so only a part of the
+    // tree is reachable.
+    let mut recovered = Vec::new();
+    let report = tree
+        .salvage(|key, value| recovered.push((key, value)))
+        .expect("salvage");
+    assert!(
+        !report.missing.is_empty(),
+        "some subtrees should be missing"
+    );
+    assert!(!recovered.is_empty(), "some keys should be recovered");
+    assert_eq!(report.recovered, recovered.len());
+
+    let mut sorted = recovered.clone();
+    sorted.sort();
+    assert_eq!(recovered, sorted, "keys should be recovered in order");
+
+    let has_prefix = |key: &Key, missing: &MissingSubtree| {
+        key.bit_length() >= missing.prefix_bit_length
+            && (0..missing.prefix_bit_length)
+                .all(|bit| key.get_bit(bit) == missing.prefix.get_bit(bit))
+    };
+    for (key, value) in keys.iter().zip(values.iter()) {
+        match recovered.iter().find(|(k, _)| k == key) {
+            Some((_, v)) => assert_eq!(v, value, "recovered value should be correct"),
+            None => assert!(
+                report.missing.iter().any(|m| has_prefix(key, m)),
+                "lost key should be covered by a missing subtree"
+            ),
+        }
+    }
+    for missing in &report.missing {
+        assert!(!missing.hash.is_empty());
+        assert!(keys.iter().any(|key| has_prefix(key, missing)));
+    }
+
+    // A fully cached tree is recovered completely.
+    let mut tree = Tree::builder()
+        .with_root_type(RootType::State)
+        .build(Box::new(NoopReadSyncer));
+    for (key, value) in keys.iter().zip(values.iter()) {
+        tree.insert(key, value).expect("insert");
+    }
+    let report = tree.salvage(|_, _| {}).expect("salvage");
+    assert_eq!(report.recovered, keys.len());
+    assert!(report.missing.is_empty());
+}
+
+#[test]
+fn test_balance_report() {
+    let mut tree = Tree::builder()
+        .with_root_type(RootType::State)
+        .build(Box::new(NoopReadSyncer));
+
+    // Empty tree.
+    let report = tree.balance_report().expect("balance_report");
+    assert_eq!(report, BalanceReport::default());
+
+    let (keys, values) = generate_key_value_pairs();
+    for (key, value) in keys.iter().zip(values.iter()) {
+        tree.insert(key, value).expect("insert");
+    }
+    let report = tree.balance_report().expect("balance_report");
+    assert_eq!(report.leaves, INSERT_ITEMS);
+    assert_eq!(report.histogram.iter().sum::<usize>(), INSERT_ITEMS);
+    assert_eq!(report.histogram.len(), report.max_depth + 1);
+    assert!(report.histogram[report.min_depth] > 0);
+    assert!(report.min_depth as f64 <= report.mean_depth);
+    assert!(report.mean_depth <= report.max_depth as f64);
+    assert!(!report.skewed, "tree should not be skewed: {:?}", report);
+
+    // Each key is a prefix of the next one, resulting in a long chain.
+    let mut tree = Tree::builder()
+        .with_root_type(RootType::State)
+        .build(Box::new(NoopReadSyncer));
+    for i in 1..=64 {
+        tree.insert(&vec![0x00; i], b"value").expect("insert");
+    }
+    let report = tree.balance_report().expect("balance_report");
+    assert_eq!(report.leaves, 64);
+    assert_eq!(report.min_depth, 1);
+    assert_eq!(report.max_depth, 63);
+    let mut expected_histogram = vec![1; 64];
+    expected_histogram[0] = 0;
+    expected_histogram[63] = 2;
+    assert_eq!(report.histogram, expected_histogram);
+    assert!(report.skewed, "tree should be skewed");
+}
+
 /// Location of the test vectors directory (from Go).
 const TEST_VECTORS_DIR: &str = "../go/storage/mkvs/testdata";
 
+#[test]
+fn test_hot_keys() {
+    let mut tree = Tree::builder()
+        .with_root_type(RootType::State)
+        .with_hot_key_tracking(3)
+        .build(Box::new(NoopReadSyncer));
+    assert!(tree.commit_stats().is_none());
+
+    // Keys diverging at the first, second, third and fourth bit, so that 0x00 and 0x10
+    // end up at the bottom of a chain of internal nodes.
+    for key in &[[0x80u8], [0x40], [0x20], [0x10], [0x00]] {
+        tree.insert(key, b"value").expect("insert");
+    }
+    tree.commit(Default::default(), 0).expect("commit");
+
+    let stats = tree
+        .commit_stats()
+        .expect("commit stats should be collected");
+    assert_eq!(
+        stats.hot_keys,
+        vec![
+            HotKey {
+                key: vec![0x00],
+                rewrites: 5
+            },
+            HotKey {
+                key: vec![0x10],
+                rewrites: 5
+            },
+            HotKey {
+                key: vec![0x20],
+                rewrites: 4
+            },
+        ]
+    );
+
+    // Only keys updated in the last commit are reported.
+    tree.insert(&[0x80], b"other value").expect("insert");
+    tree.commit(Default::default(), 1).expect("commit");
+
+    let stats = tree
+        .commit_stats()
+        .expect("commit stats should be collected");
+    assert_eq!(
+        stats.hot_keys,
+        vec![HotKey {
+            key: vec![0x80],
+            rewrites: 2
+        }]
+    );
+
+    // Tracking is disabled by default.
+    let mut tree = Tree::builder()
+        .with_root_type(RootType::State)
+        .build(Box::new(NoopReadSyncer));
+    tree.insert(b"foo", b"bar").expect("insert");
+    tree.commit(Default::default(), 0).expect("commit");
+    assert!(tree.commit_stats().is_none());
+}
+
+#[test]
+fn test_proof_node_budget() {
+    const DEPTH: usize = 50;
+
+    let build_tree = |budget| {
+        let mut tree = Tree::builder()
+            .with_root_type(RootType::State)
+            .with_proof_node_budget(budget)
+            .build(Box::new(NoopReadSyncer));
+
+        // Each key is a prefix of the next one, so the tree degenerates into a chain of
+        // internal nodes, each with a leaf node and a single child.
+        for i in 1..=DEPTH {
+            tree.insert(&vec![0x00; i], b"value").expect("insert");
+        }
+        tree.commit(Default::default(), 0).expect("commit");
+        tree
+    };
+    let deepest_key = vec![0x00; DEPTH];
+
+    // The path to the deepest key consists of DEPTH - 1 internal nodes and a leaf.
+    let tree = build_tree(DEPTH);
+    assert!(tree.get_proof(&deepest_key).expect("get_proof").is_some());
+    let tree = build_tree(0);
+    assert!(tree.get_proof(&deepest_key).expect("get_proof").is_some());
+
+    for _ in 0..2 {
+        let tree = build_tree(DEPTH - 1);
+        let err = tree
+            .get_proof(&deepest_key)
+            .expect_err("get_proof should exceed the budget");
+        match err.downcast_ref::<TreeError>() {
+            Some(TreeError::ProofBudgetExceeded {
+                budget,
+                cache_hits,
+                fetches,
+                bit_depth,
+            }) => {
+                assert_eq!(*budget, DEPTH - 1);
+                assert_eq!(*cache_hits, DEPTH - 1);
+                assert_eq!(*fetches, 0);
+                assert_eq!(*bit_depth as usize, (DEPTH - 1) * 8);
+            }
+            _ => panic!("unexpected error: {:?}", err),
+        }
+
+        // Shallow keys are still within budget.
+        assert!(tree.get_proof(&[0x00]).expect("get_proof").is_some());
+    }
+}
+
+#[test]
+fn test_storage_delta() {
+    let (keys, values) = generate_key_value_pairs();
+
+    let build_tree = |keys: &[Vec<u8>], values: &[Vec<u8>]| {
+        let mut tree = Tree::builder()
+            .with_root_type(RootType::State)
+            .with_hot_key_tracking(1)
+            .build(Box::new(NoopReadSyncer));
+        for (key, value) in keys.iter().zip(values.iter()) {
+            tree.insert(key, value).expect("insert");
+        }
+        let hash = tree.commit(Default::default(), 0).expect("commit");
+        (tree, hash)
+    };
+    let root = |hash| Root {
+        root_type: RootType::State,
+        hash,
+        ..Default::default()
+    };
+    // A read syncer serving the nodes of all given trees, recording fetched positions.
+    let positions = Rc::new(RefCell::new(Vec::new()));
+    let read_syncer = |trees: &[&Tree]| {
+        let mut read_syncer = LocalReadSyncer::new(trees[0], positions.clone());
+        for tree in &trees[1..] {
+            read_syncer
+                .nodes
+                .extend(LocalReadSyncer::new(tree, positions.clone()).nodes);
+        }
+        Box::new(read_syncer)
+    };
+
+    let (from, from_hash) = build_tree(&keys, &values);
+    let (mut to, _) = build_tree(&keys, &values);
+
+    // Identical roots.
+    let delta = storage_delta(read_syncer(&[&from]), root(from_hash), root(from_hash))
+        .expect("storage_delta");
+    assert_eq!(delta, StorageDelta::default());
+    assert!(positions.borrow().is_empty(), "nothing should be fetched");
+
+    // Changing a single value rewrites exactly the path to its leaf.
+    to.insert(&keys[42], b"value 24").expect("insert");
+    let to_hash = to.commit(Default::default(), 1).expect("commit");
+    let rewrites = to.commit_stats().unwrap().hot_keys[0].rewrites;
+
+    let delta = storage_delta(read_syncer(&[&from, &to]), root(from_hash), root(to_hash))
+        .expect("storage_delta");
+    assert_eq!(delta.added_nodes, rewrites);
+    assert_eq!(delta.removed_nodes, rewrites);
+    assert!(delta.added_bytes > 0);
+    assert_eq!(delta.added_bytes, delta.removed_bytes);
+    // Only the rewritten paths are fetched, shared subtrees are skipped.
+    assert!(
+        positions.borrow().len() <= 2 * rewrites,
+        "fetched {} nodes for {} rewrites",
+        positions.borrow().len(),
+        rewrites
+    );
+
+    // Unrelated roots.
+    let empty = root(Hash::empty_hash());
+    let delta =
+        storage_delta(read_syncer(&[&from]), empty, root(from_hash)).expect("storage_delta");
+    assert_eq!(delta.added_nodes, 2 * INSERT_ITEMS - 1);
+    assert_eq!(delta.removed_nodes, 0);
+    let delta =
+        storage_delta(read_syncer(&[&from]), root(from_hash), empty).expect("storage_delta");
+    assert_eq!(delta.added_nodes, 0);
+    assert_eq!(delta.removed_nodes, 2 * INSERT_ITEMS - 1);
+
+    let (other_keys, other_values) = generate_key_value_pairs_ex("other ".to_string(), 10);
+    let (other, other_hash) = build_tree(&other_keys, &other_values);
+    let delta = storage_delta(
+        read_syncer(&[&from, &other]),
+        root(from_hash),
+        root(other_hash),
+    )
+    .expect("storage_delta");
+    assert_eq!(delta.added_nodes, 2 * other_keys.len() - 1);
+    assert_eq!(delta.removed_nodes, 2 * INSERT_ITEMS - 1);
+}
+
+#[test]
+fn test_storage_profile() {
+    let profile = |tree: &Tree, max_depth: usize| -> Vec<(String, usize)> {
+        let mut out = Vec::new();
+        tree.storage_profile(max_depth, &mut out)
+            .expect("storage_profile");
+        String::from_utf8(out)
+            .expect("utf-8 output")
+            .lines()
+            .map(|line| {
+                let (frames, size) = line.rsplit_once(' ').expect("folded line");
+                (frames.to_string(), size.parse().expect("size"))
+            })
+            .collect()
+    };
+    let total_bytes = |tree: &Tree| {
+        let mut total = 0;
+        tree.walk_nodes(|_, node_ref, _| {
+            total += node_ref.borrow().marshal_binary()?.len();
+            Ok(true)
+        })
+        .expect("walk_nodes");
+        total
+    };
+
+    let mut tree = Tree::builder()
+        .with_root_type(RootType::State)
+        .build(Box::new(NoopReadSyncer));
+    for key in &[b"a/1", b"a/2", b"b/1"] {
+        tree.insert(*key, b"value").expect("insert");
+    }
+    tree.commit(Default::default(), 0).expect("commit");
+
+    // The root is attributed to the empty prefix, everything else to the first byte.
+    let lines = profile(&tree, 1);
+    let frames: Vec<&str> = lines.iter().map(|(frames, _)| frames.as_str()).collect();
+    assert_eq!(frames, vec!["mkvs", "mkvs;61", "mkvs;62"]);
+    let sizes: usize = lines.iter().map(|(_, size)| size).sum();
+    assert_eq!(sizes, total_bytes(&tree));
+
+    let (keys, values) = generate_key_value_pairs();
+    for (key, value) in keys.iter().zip(values.iter()) {
+        tree.insert(key, value).expect("insert");
+    }
+    tree.commit(Default::default(), 1).expect("commit");
+
+    for max_depth in 0..6 {
+        let lines = profile(&tree, max_depth);
+        let sizes: usize = lines.iter().map(|(_, size)| size).sum();
+        assert_eq!(sizes, total_bytes(&tree), "max depth {}", max_depth);
+        for (frames, _) in &lines {
+            assert!(frames.split(';').count() <= max_depth + 1);
+        }
+    }
+}
+
+#[test]
+fn test_nibble_histogram() {
+    let mut tree = Tree::builder()
+        .with_root_type(RootType::State)
+        .build(Box::new(NoopReadSyncer));
+    assert!(tree
+        .nibble_histogram(1)
+        .expect("nibble_histogram")
+        .is_empty());
+
+    // Most keys start with "h" (0x68), one key starts with each other nibble.
+    for i in 0..90 {
+        tree.insert(format!("hot/{}", i).as_bytes(), b"value")
+            .expect("insert");
+    }
+    let cold: Vec<u8> = (0..16).filter(|&n| n != 6).collect();
+    for n in &cold {
+        tree.insert(&[n << 4, 0xff], b"value").expect("insert");
+    }
+    tree.commit(Default::default(), 0).expect("commit");
+
+    assert_eq!(
+        tree.nibble_histogram(0).expect("nibble_histogram"),
+        vec![(vec![], 105)]
+    );
+
+    let histogram = tree.nibble_histogram(1).expect("nibble_histogram");
+    let mut expected: Vec<(NibblePrefix, usize)> = cold.iter().map(|&n| (vec![n], 1)).collect();
+    expected.push((vec![6], 90));
+    expected.sort();
+    assert_eq!(histogram, expected);
+
+    let histogram = tree.nibble_histogram(2).expect("nibble_histogram");
+    let mut expected: Vec<(NibblePrefix, usize)> = cold.iter().map(|&n| (vec![n, 0], 1)).collect();
+    expected.push((vec![6, 8], 90));
+    expected.sort();
+    assert_eq!(histogram, expected);
+
+    // Prefixes are limited by the key length.
+    let histogram = tree.nibble_histogram(64).expect("nibble_histogram");
+    assert_eq!(histogram.len(), 105);
+    assert!(histogram.contains(&(vec![0, 0, 0xf, 0xf], 1)));
+}
+
 fn test_special_case_from_json(fixture: &'static str) {
     let server = ProtocolServer::new(None);
 
@@ -0,0 +1,577 @@
+use std::any::Any;
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    common::crypto::hash::Hash,
+    storage::mkvs::{
+        cache::Cache,
+        sync::{
+            GetPrefixesRequest, GetRequest, IterateRequest, Proof, ProofBuilder, ProofResponse,
+            ProofVerifier, ReadSync,
+        },
+        tree::{Depth, Key, KeyTrait, NodeBox, NodePtrRef, NodeRef, Root, Tree, TreeError, Value},
+        WriteLog,
+    },
+};
+
+use super::lookup::FetcherSyncGet;
+
+/// A proof of all the nodes of a tree needed to apply a write log to it.
+///
+/// See `Tree::witness` and `stateless_apply`.
+pub type Witness = Proof;
+
+/// Verify that applying the given write log to the tree at `old_root` results in the
+/// `claimed` root hash.
+///
+/// Any nodes needed to apply the write log are fetched (and verified) via the given read
+/// syncer. Updated nodes are only kept in memory, nothing is ever persisted.
+///
+/// Returns `false` if the claimed root does not match. In case the read syncer is unable to
+/// provide the nodes needed to apply the write log, `TreeError::IncompleteState` is returned.
+/// Other errors, such as proofs from the read syncer failing verification, are returned as
+/// they are.
+pub fn verify_write_log(
+    read_syncer: Box<dyn ReadSync>,
+    old_root: Root,
+    write_log: &WriteLog,
+    claimed: &Hash,
+) -> Result<bool> {
+    let tree = Tree::builder()
+        .with_capacity(0, 0)
+        .with_root(old_root)
+        .build(Box::new(IncompleteStateReadSyncer { inner: read_syncer }));
+
+    let new_hash = apply_write_log(tree, old_root, write_log)?;
+    Ok(&new_hash == claimed)
+}
+
+/// Apply the given write log to the tree at `old_root` using only the nodes included in the
+/// witness, returning the new root hash.
+///
+/// The witness is verified against `old_root`. In case it does not contain all of the nodes
+/// needed to apply the write log, `TreeError::IncompleteState` is returned.
+pub fn stateless_apply(old_root: Root, write_log: &WriteLog, witness: &Witness) -> Result<Hash> {
+    let tree = Tree::builder()
+        .with_capacity(0, 0)
+        .with_root(old_root)
+        .build(Box::new(WitnessReadSyncer {
+            witness: Some(witness.clone()),
+        }));
+
+    // Load all nodes from the witness before the tree is modified, as verified subtrees can
+    // only be merged into clean parts of the tree.
+    let pending_root = tree.cache.borrow().get_pending_root();
+    tree.cache
+        .borrow_mut()
+        .deref_node_ptr(pending_root, Some(FetcherSyncGet::new(&Key::new(), false)))?;
+
+    apply_write_log(tree, old_root, write_log)
+}
+
+fn apply_write_log(mut tree: Tree, old_root: Root, write_log: &WriteLog) -> Result<Hash> {
+    for entry in write_log {
+        let result = match entry.value {
+            Some(ref value) => tree.insert(&entry.key, value),
+            None => tree.remove(&entry.key),
+        };
+        result?;
+    }
+
+    tree.commit(old_root.namespace, old_root.version + 1)
+}
+
+/// A read syncer which provides the witness in response to the first request and fails
+/// any further requests, as these are for nodes not included in the witness.
+struct WitnessReadSyncer {
+    witness: Option<Witness>,
+}
+
+impl WitnessReadSyncer {
+    fn take_witness(&mut self) -> Result<ProofResponse> {
+        match self.witness.take() {
+            Some(proof) => Ok(ProofResponse { proof }),
+            None => {
+                Err(TreeError::IncompleteState("node not included in witness".to_string()).into())
+            }
+        }
+    }
+}
+
+impl ReadSync for WitnessReadSyncer {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn sync_get(&mut self, _request: GetRequest) -> Result<ProofResponse> {
+        self.take_witness()
+    }
+
+    fn sync_get_prefixes(&mut self, _request: GetPrefixesRequest) -> Result<ProofResponse> {
+        self.take_witness()
+    }
+
+    fn sync_iterate(&mut self, _request: IterateRequest) -> Result<ProofResponse> {
+        self.take_witness()
+    }
+}
+
+/// A read syncer which reports any failure of the wrapped read syncer to provide nodes as
+/// `TreeError::IncompleteState`. Proofs it does return are still verified by the tree, so
+/// invalid proofs are not reported as incomplete state.
+struct IncompleteStateReadSyncer {
+    inner: Box<dyn ReadSync>,
+}
+
+fn incomplete_state(err: anyhow::Error) -> anyhow::Error {
+    TreeError::IncompleteState(err.to_string()).into()
+}
+
+impl ReadSync for IncompleteStateReadSyncer {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn sync_get(&mut self, request: GetRequest) -> Result<ProofResponse> {
+        self.inner.sync_get(request).map_err(incomplete_state)
+    }
+
+    fn sync_get_prefixes(&mut self, request: GetPrefixesRequest) -> Result<ProofResponse> {
+        self.inner
+            .sync_get_prefixes(request)
+            .map_err(incomplete_state)
+    }
+
+    fn sync_iterate(&mut self, request: IterateRequest) -> Result<ProofResponse> {
+        self.inner.sync_iterate(request).map_err(incomplete_state)
+    }
+}
+
+/// Verify a proof generated by `Tree::prove_pair` against the given root, returning the values
+/// of both keys (`None` for keys proven to be absent).
+///
+/// Fails in case the proof is invalid or does not cover both keys.
+pub fn verify_pair_proof(
+    root: Hash,
+    proof: &Proof,
+    key_a: &[u8],
+    key_b: &[u8],
+) -> Result<(Option<Value>, Option<Value>)> {
+    let subtree = ProofVerifier.verify_proof(root, proof)?;
+    Ok((
+        lookup_verified(subtree.clone(), 0, &key_a.to_vec())?,
+        lookup_verified(subtree, 0, &key_b.to_vec())?,
+    ))
+}
+
+/// Look up a key in a verified subtree, failing if the subtree does not contain all the nodes
+/// needed to determine the value.
+fn lookup_verified(ptr: NodePtrRef, bit_depth: Depth, key: &Key) -> Result<Option<Value>> {
+    let node_ref = {
+        let ptr = ptr.borrow();
+        match ptr.node {
+            Some(ref node_ref) => node_ref.clone(),
+            None if ptr.is_null() => return Ok(None),
+            None => return Err(anyhow!("mkvs: proof does not cover key")),
+        }
+    };
+
+    let node = node_ref.borrow();
+    match *node {
+        NodeBox::Internal(ref n) => {
+            let bit_length = bit_depth + n.label_bit_length;
+            if key.bit_length() < bit_length {
+                Ok(None)
+            } else if key.bit_length() == bit_length {
+                lookup_verified(n.leaf_node.clone(), bit_length, key)
+            } else if key.get_bit(bit_length) {
+                lookup_verified(n.right.clone(), bit_length, key)
+            } else {
+                lookup_verified(n.left.clone(), bit_length, key)
+            }
+        }
+        NodeBox::Leaf(ref n) => {
+            if n.key == *key {
+                Ok(Some(n.value.clone()))
+            } else {
+                Ok(None)
+            }
+        }
+    }
+}
+
+impl Tree {
+    /// Generate a witness containing all the nodes needed to insert or remove any of the
+    /// given keys, for use with `stateless_apply`.
+    ///
+    /// Besides the nodes on the path to each key, the witness includes all direct children of
+    /// the internal nodes on the path, as these are needed when a removal collapses a node.
+    /// The tree must not have any uncommitted changes.
+    pub fn witness(&self, keys: &[&[u8]]) -> Result<Witness> {
+        let pending_root = self.cache.borrow().get_pending_root();
+        if !pending_root.borrow().clean && pending_root.borrow().node.is_some() {
+            return Err(anyhow!("mkvs: witness requires a committed tree"));
+        }
+
+        let mut proof_builder = ProofBuilder::new(pending_root.borrow().hash);
+        for key in keys {
+            // Remember where the path from root to target node ends (will end).
+            self.cache.borrow_mut().mark_position();
+
+            self._witness(pending_root.clone(), 0, &key.to_vec(), &mut proof_builder)?;
+        }
+        Ok(proof_builder.build())
+    }
+
+    fn _witness(
+        &self,
+        ptr: NodePtrRef,
+        bit_depth: Depth,
+        key: &Key,
+        proof_builder: &mut ProofBuilder,
+    ) -> Result<()> {
+        let node_ref = match self._witness_include(ptr, key, proof_builder)? {
+            Some(node_ref) => node_ref,
+            None => return Ok(()),
+        };
+
+        let (bit_length, children) = match *node_ref.borrow() {
+            NodeBox::Internal(ref n) => (
+                bit_depth + n.label_bit_length,
+                [n.leaf_node.clone(), n.left.clone(), n.right.clone()],
+            ),
+            NodeBox::Leaf(_) => return Ok(()),
+        };
+
+        for child in &children {
+            self._witness_include(child.clone(), key, proof_builder)?;
+        }
+
+        // Continue along the path to the key.
+        let [leaf_node, left, right] = children;
+        if key.bit_length() == bit_length {
+            self._witness(leaf_node, bit_length, key, proof_builder)
+        } else if key.bit_length() < bit_length {
+            Ok(())
+        } else if key.get_bit(bit_length) {
+            self._witness(right, bit_length, key, proof_builder)
+        } else {
+            self._witness(left, bit_length, key, proof_builder)
+        }
+    }
+
+    /// Include the given node in the witness. For internal nodes, the leaf node is included as
+    /// well as internal nodes are only usable together with their leaf node.
+    fn _witness_include(
+        &self,
+        ptr: NodePtrRef,
+        key: &Key,
+        proof_builder: &mut ProofBuilder,
+    ) -> Result<Option<NodeRef>> {
+        let node_ref = match self
+            .cache
+            .borrow_mut()
+            .deref_node_ptr(ptr, Some(FetcherSyncGet::new(key, true)))?
+        {
+            Some(node_ref) => node_ref,
+            None => return Ok(None),
+        };
+        proof_builder.include(&node_ref.borrow());
+
+        let leaf_node = match *node_ref.borrow() {
+            NodeBox::Internal(ref n) => Some(n.leaf_node.clone()),
+            NodeBox::Leaf(_) => None,
+        };
+        if let Some(leaf_node) = leaf_node {
+            if let Some(leaf_ref) = self
+                .cache
+                .borrow_mut()
+                .deref_node_ptr(leaf_node, Some(FetcherSyncGet::new(key, true)))?
+            {
+                proof_builder.include(&leaf_ref.borrow());
+            }
+        }
+
+        Ok(Some(node_ref))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+    use crate::storage::mkvs::{sync::NoopReadSyncer, LogEntry, OverlayTree, RootType};
+
+    fn generate_write_log() -> WriteLog {
+        (0..100)
+            .map(|i| {
+                LogEntry::new(
+                    format!("key {}", i).as_bytes(),
+                    format!("value {}", i).as_bytes(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_verify_write_log() {
+        let write_log = generate_write_log();
+
+        let mut tree = Tree::builder()
+            .with_root_type(RootType::State)
+            .build(Box::new(NoopReadSyncer));
+        for entry in &write_log {
+            tree.insert(&entry.key, entry.value.as_ref().unwrap())
+                .expect("insert");
+        }
+        let new_hash = tree.commit(Default::default(), 1).expect("commit");
+
+        let old_root = Root {
+            root_type: RootType::State,
+            hash: Hash::empty_hash(),
+            ..Default::default()
+        };
+
+        // Correct claim.
+        let valid = verify_write_log(Box::new(NoopReadSyncer), old_root, &write_log, &new_hash)
+            .expect("verify_write_log");
+        assert!(valid, "correct claim should verify");
+
+        // Empty write log only matches the old root.
+        let valid = verify_write_log(
+            Box::new(NoopReadSyncer),
+            old_root,
+            &WriteLog::new(),
+            &Hash::empty_hash(),
+        )
+        .expect("verify_write_log");
+        assert!(valid, "empty write log should verify against the old root");
+
+        // Subtly wrong claim, one value differs.
+        let mut wrong_write_log = write_log.clone();
+        wrong_write_log[42].value = Some(b"value 43".to_vec());
+        let valid = verify_write_log(
+            Box::new(NoopReadSyncer),
+            old_root,
+            &wrong_write_log,
+            &new_hash,
+        )
+        .expect("verify_write_log");
+        assert!(!valid, "wrong claim should not verify");
+    }
+
+    #[test]
+    fn test_verify_write_log_incomplete_state() {
+        let write_log = generate_write_log();
+
+        // Old root is not available via the read syncer.
+        let old_root = Root {
+            root_type: RootType::State,
+            hash: Hash::digest_bytes(b"missing root"),
+            ..Default::default()
+        };
+
+        let err = verify_write_log(
+            Box::new(NoopReadSyncer),
+            old_root,
+            &write_log,
+            &Hash::empty_hash(),
+        )
+        .expect_err("verify_write_log should fail");
+        assert!(matches!(
+            err.downcast_ref::<TreeError>(),
+            Some(TreeError::IncompleteState(_))
+        ));
+    }
+
+    /// A read syncer which answers every request with the same proof.
+    struct FixedReadSyncer {
+        proof: Proof,
+    }
+
+    impl ReadSync for FixedReadSyncer {
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+
+        fn sync_get(&mut self, _request: GetRequest) -> Result<ProofResponse> {
+            Ok(ProofResponse {
+                proof: self.proof.clone(),
+            })
+        }
+
+        fn sync_get_prefixes(&mut self, _request: GetPrefixesRequest) -> Result<ProofResponse> {
+            self.sync_get(Default::default())
+        }
+
+        fn sync_iterate(&mut self, _request: IterateRequest) -> Result<ProofResponse> {
+            self.sync_get(Default::default())
+        }
+    }
+
+    #[test]
+    fn test_verify_write_log_forged_proof() {
+        let mut tree = Tree::builder()
+            .with_root_type(RootType::State)
+            .build(Box::new(NoopReadSyncer));
+        for entry in &generate_write_log() {
+            tree.insert(&entry.key, entry.value.as_ref().unwrap())
+                .expect("insert");
+        }
+        let hash = tree.commit(Default::default(), 1).expect("commit");
+        let old_root = Root {
+            root_type: RootType::State,
+            hash,
+            version: 1,
+            ..Default::default()
+        };
+
+        // Proof with a tampered node, which fails verification against the old root.
+        let mut proof = tree.witness(&[b"key 1"]).expect("witness");
+        let entry = proof
+            .entries
+            .iter_mut()
+            .flatten()
+            .last()
+            .expect("proof has entries");
+        *entry.last_mut().expect("entry is not empty") ^= 0x01;
+
+        let write_log = vec![LogEntry::new(b"key 1", b"updated")];
+        let err = verify_write_log(
+            Box::new(FixedReadSyncer { proof }),
+            old_root,
+            &write_log,
+            &Hash::empty_hash(),
+        )
+        .expect_err("verify_write_log should fail");
+        assert!(
+            !matches!(
+                err.downcast_ref::<TreeError>(),
+                Some(TreeError::IncompleteState(_))
+            ),
+            "forged proof should not be reported as incomplete state: {:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_stateless_apply() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut tree = OverlayTree::new(
+            Tree::builder()
+                .with_root_type(RootType::State)
+                .build(Box::new(NoopReadSyncer)),
+        );
+
+        let mut old_root = Root {
+            root_type: RootType::State,
+            hash: Hash::empty_hash(),
+            ..Default::default()
+        };
+        for round in 0..20 {
+            // Random batch of inserts and removals over a small key space, so that batches
+            // both add new keys and update or remove existing ones.
+            for _ in 0..rng.gen_range(1..50) {
+                let key = format!("key {}", rng.gen_range(0..200));
+                if rng.gen_bool(0.3) {
+                    tree.remove(key.as_bytes()).expect("remove");
+                } else {
+                    let value = format!("value {} {}", key, round);
+                    tree.insert(key.as_bytes(), value.as_bytes())
+                        .expect("insert");
+                }
+            }
+
+            let (write_log, witness, new_hash) = tree
+                .commit_with_witness(Default::default(), old_root.version + 1)
+                .expect("commit_with_witness");
+            let hash = stateless_apply(old_root, &write_log, &witness).expect("stateless_apply");
+            assert_eq!(hash, new_hash, "stateless apply should match commit");
+
+            old_root.hash = new_hash;
+            old_root.version += 1;
+        }
+    }
+
+    #[test]
+    fn test_stateless_apply_borrowed_tree() {
+        let mut tree = Tree::builder()
+            .with_root_type(RootType::State)
+            .build(Box::new(NoopReadSyncer));
+        let mut overlay = OverlayTree::new(&mut tree);
+        for entry in &generate_write_log() {
+            overlay
+                .insert(&entry.key, entry.value.as_ref().unwrap())
+                .expect("insert");
+        }
+
+        let (write_log, witness, new_hash) = overlay
+            .commit_with_witness(Default::default(), 1)
+            .expect("commit_with_witness");
+        let old_root = Root {
+            root_type: RootType::State,
+            hash: Hash::empty_hash(),
+            ..Default::default()
+        };
+        let hash = stateless_apply(old_root, &write_log, &witness).expect("stateless_apply");
+        assert_eq!(hash, new_hash);
+        assert_eq!(tree.get(b"key 1").expect("get"), Some(b"value 1".to_vec()));
+    }
+
+    #[test]
+    fn test_stateless_apply_incomplete_witness() {
+        let mut tree = Tree::builder()
+            .with_root_type(RootType::State)
+            .build(Box::new(NoopReadSyncer));
+        for entry in &generate_write_log() {
+            tree.insert(&entry.key, entry.value.as_ref().unwrap())
+                .expect("insert");
+        }
+        let hash = tree.commit(Default::default(), 1).expect("commit");
+        let old_root = Root {
+            root_type: RootType::State,
+            hash,
+            version: 1,
+            ..Default::default()
+        };
+
+        let write_log = vec![
+            LogEntry::new(b"key 1", b"updated"),
+            LogEntry {
+                key: b"key 2".to_vec(),
+                value: None,
+            },
+        ];
+
+        // Witness which omits the nodes needed for one of the keys.
+        let witness = tree.witness(&[b"key 1"]).expect("witness");
+        let err = stateless_apply(old_root, &write_log, &witness)
+            .expect_err("stateless_apply should fail");
+        assert!(matches!(
+            err.downcast_ref::<TreeError>(),
+            Some(TreeError::IncompleteState(_))
+        ));
+
+        // Witness for a different root.
+        let witness = tree.witness(&[b"key 1", b"key 2"]).expect("witness");
+        let other_root = Root {
+            hash: Hash::digest_bytes(b"other root"),
+            ..old_root
+        };
+        let err = stateless_apply(other_root, &write_log, &witness)
+            .expect_err("stateless_apply should fail");
+        assert!(!matches!(
+            err.downcast_ref::<TreeError>(),
+            Some(TreeError::IncompleteState(_))
+        ));
+
+        // Complete witness.
+        stateless_apply(old_root, &write_log, &witness).expect("stateless_apply");
+
+        // Witnesses require a committed tree.
+        tree.insert(b"foo", b"bar").expect("insert");
+        assert!(tree.witness(&[b"foo"]).is_err());
+    }
+}
@@ -0,0 +1,114 @@
+use anyhow::{Error, Result};
+
+use crate::storage::mkvs::{
+    cache::Cache,
+    tree::{Depth, Key, KeyTrait, NodeBox, NodePtrRef, NodeRef, Tree},
+};
+
+use super::iterator::FetcherSyncIterate;
+
+impl Tree {
+    /// Walk all nodes of the tree in depth-first pre-order, fetching any missing nodes via
+    /// the read syncer.
+    ///
+    /// The visitor is called with the pointer to each node, the node itself and its depth
+    /// (the number of internal nodes above it). If it returns `false`, the subtree below the
+    /// node is skipped.
+    pub(super) fn walk_nodes<F>(&self, visit: F) -> Result<()>
+    where
+        F: FnMut(&NodePtrRef, &NodeRef, usize) -> Result<bool>,
+    {
+        self.walk_nodes_with_missing(visit, |_, _, _, err| Err(err))
+    }
+
+    /// Walk all nodes of the tree like `walk_nodes`, but call `missing` for any node that
+    /// cannot be dereferenced, with the pointer to the node, the key prefix covered by it and
+    /// the bit length of that prefix, and the error. If `missing` returns `Ok`, the walk
+    /// continues with the next node.
+    pub(super) fn walk_nodes_with_missing<F, M>(&self, mut visit: F, mut missing: M) -> Result<()>
+    where
+        F: FnMut(&NodePtrRef, &NodeRef, usize) -> Result<bool>,
+        M: FnMut(&NodePtrRef, &Key, Depth, Error) -> Result<()>,
+    {
+        let pending_root = self.cache.borrow().get_pending_root();
+
+        // Remember where the path from root to target node ends (will end).
+        self.cache.borrow_mut().mark_position();
+
+        self._walk_nodes(
+            pending_root,
+            0,
+            0,
+            (Key::new(), 0),
+            &mut visit,
+            &mut missing,
+        )
+    }
+
+    pub(super) fn _walk_nodes<F, M>(
+        &self,
+        ptr: NodePtrRef,
+        bit_depth: Depth,
+        depth: usize,
+        (path, path_bits): (Key, Depth),
+        visit: &mut F,
+        missing: &mut M,
+    ) -> Result<()>
+    where
+        F: FnMut(&NodePtrRef, &NodeRef, usize) -> Result<bool>,
+        M: FnMut(&NodePtrRef, &Key, Depth, Error) -> Result<()>,
+    {
+        let result = self
+            .cache
+            .borrow_mut()
+            .deref_node_ptr(ptr.clone(), Some(FetcherSyncIterate::new(&path, 0)));
+        let node_ref = match result {
+            Ok(Some(node_ref)) => node_ref,
+            Ok(None) => return Ok(()),
+            Err(err) => return missing(&ptr, &path, path_bits, err),
+        };
+
+        if !visit(&ptr, &node_ref, depth)? {
+            return Ok(());
+        }
+
+        let children = match *node_ref.borrow() {
+            NodeBox::Internal(ref n) => {
+                let bit_length = bit_depth + n.label_bit_length;
+                let new_path = path.merge(bit_depth, &n.label, n.label_bit_length);
+                Some((
+                    bit_length,
+                    [
+                        (n.leaf_node.clone(), new_path.clone(), bit_length),
+                        (
+                            n.left.clone(),
+                            new_path.append_bit(bit_length, false),
+                            bit_length + 1,
+                        ),
+                        (
+                            n.right.clone(),
+                            new_path.append_bit(bit_length, true),
+                            bit_length + 1,
+                        ),
+                    ],
+                ))
+            }
+            NodeBox::Leaf(_) => None,
+        };
+
+        if let Some((bit_length, children)) = children {
+            for (child, child_path, child_path_bits) in children {
+                self._walk_nodes(
+                    child,
+                    bit_length,
+                    depth + 1,
+                    (child_path, child_path_bits),
+                    visit,
+                    missing,
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,204 @@
+//! Typed wrapper over a byte-level MKVS.
+use std::marker::PhantomData;
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    common::{crypto::hash::Hash, namespace::Namespace},
+    storage::mkvs::FallibleMKVS,
+};
+
+/// A codec for converting values to and from their stored representation.
+pub trait Codec<T> {
+    /// Encode the given value.
+    fn encode(&self, value: &T) -> Vec<u8>;
+
+    /// Decode a stored value.
+    fn decode(&self, data: &[u8]) -> Result<T>;
+}
+
+/// A codec storing values in their CBOR encoding.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CborCodec;
+
+impl<T> Codec<T> for CborCodec
+where
+    T: cbor::Encode + cbor::Decode + Clone,
+{
+    fn encode(&self, value: &T) -> Vec<u8> {
+        cbor::to_vec(value.clone())
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<T> {
+        cbor::from_slice(data).map_err(|err| anyhow!("mkvs: failed to decode value: {}", err))
+    }
+}
+
+/// A thin wrapper over an MKVS which stores values of type `T`, encoding them with a codec.
+pub struct TypedTrie<M, T, C = CborCodec> {
+    mkvs: M,
+    codec: C,
+    _value: PhantomData<T>,
+}
+
+impl<M, T> TypedTrie<M, T, CborCodec>
+where
+    M: FallibleMKVS,
+    T: cbor::Encode + cbor::Decode + Clone,
+{
+    /// Create a new typed wrapper storing CBOR-encoded values.
+    pub fn new(mkvs: M) -> Self {
+        Self::with_codec(mkvs, CborCodec)
+    }
+}
+
+impl<M, T, C> TypedTrie<M, T, C>
+where
+    M: FallibleMKVS,
+    C: Codec<T>,
+{
+    /// Create a new typed wrapper using the given codec.
+    pub fn with_codec(mkvs: M, codec: C) -> Self {
+        Self {
+            mkvs,
+            codec,
+            _value: PhantomData,
+        }
+    }
+
+    /// Fetch and decode the entry with the given key.
+    pub fn get(&self, key: &[u8]) -> Result<Option<T>> {
+        self.decode(self.mkvs.get(key)?)
+    }
+
+    /// Encode and insert the entry with the given key, returning the previous value if any.
+    pub fn insert(&mut self, key: &[u8], value: &T) -> Result<Option<T>> {
+        let data = self.codec.encode(value);
+        let previous = self.mkvs.insert(key, &data)?;
+        self.decode(previous)
+    }
+
+    /// Remove the entry with the given key, returning the previous value if any.
+    pub fn remove(&mut self, key: &[u8]) -> Result<Option<T>> {
+        let previous = self.mkvs.remove(key)?;
+        self.decode(previous)
+    }
+
+    /// Commit all changes to the underlying MKVS.
+    pub fn commit(&mut self, namespace: Namespace, version: u64) -> Result<Hash> {
+        self.mkvs.commit(namespace, version)
+    }
+
+    /// Return a reference to the underlying MKVS.
+    pub fn inner(&self) -> &M {
+        &self.mkvs
+    }
+
+    /// Return a mutable reference to the underlying MKVS.
+    pub fn inner_mut(&mut self) -> &mut M {
+        &mut self.mkvs
+    }
+
+    /// Consume the wrapper, returning the underlying MKVS.
+    pub fn into_inner(self) -> M {
+        self.mkvs
+    }
+
+    fn decode(&self, data: Option<Vec<u8>>) -> Result<Option<T>> {
+        data.map(|data| self.codec.decode(&data)).transpose()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::convert::TryInto;
+
+    use super::*;
+    use crate::storage::mkvs::{sync::NoopReadSyncer, RootType, Tree};
+
+    #[derive(Clone, Debug, PartialEq, Eq, cbor::Encode, cbor::Decode)]
+    struct Account {
+        balance: u64,
+        nonce: u64,
+    }
+
+    /// A codec storing accounts as two big-endian integers.
+    struct FixedCodec;
+
+    impl Codec<Account> for FixedCodec {
+        fn encode(&self, value: &Account) -> Vec<u8> {
+            [value.balance.to_be_bytes(), value.nonce.to_be_bytes()].concat()
+        }
+
+        fn decode(&self, data: &[u8]) -> Result<Account> {
+            if data.len() != 16 {
+                return Err(anyhow!("bad account length"));
+            }
+            Ok(Account {
+                balance: u64::from_be_bytes(data[..8].try_into().unwrap()),
+                nonce: u64::from_be_bytes(data[8..].try_into().unwrap()),
+            })
+        }
+    }
+
+    fn new_tree() -> Tree {
+        Tree::builder()
+            .with_root_type(RootType::State)
+            .build(Box::new(NoopReadSyncer))
+    }
+
+    fn check_round_trip<C: Codec<Account>>(codec: C) {
+        let mut trie = TypedTrie::with_codec(new_tree(), codec);
+        let alice = Account {
+            balance: 100,
+            nonce: 1,
+        };
+        let bob = Account {
+            balance: 5,
+            nonce: 0,
+        };
+
+        assert_eq!(trie.insert(b"alice", &alice).unwrap(), None);
+        assert_eq!(trie.insert(b"bob", &bob).unwrap(), None);
+        let hash = trie.commit(Default::default(), 0).unwrap();
+
+        assert_eq!(trie.get(b"alice").unwrap(), Some(alice.clone()));
+        assert_eq!(trie.get(b"bob").unwrap(), Some(bob.clone()));
+        assert_eq!(trie.get(b"carol").unwrap(), None);
+
+        // The wrapper only stores the encoded values.
+        let codec = &trie.codec;
+        let mut tree = new_tree();
+        tree.insert(b"alice", &codec.encode(&alice)).unwrap();
+        tree.insert(b"bob", &codec.encode(&bob)).unwrap();
+        assert_eq!(tree.commit(Default::default(), 0).unwrap(), hash);
+
+        let updated = Account {
+            balance: 90,
+            nonce: 2,
+        };
+        assert_eq!(trie.insert(b"alice", &updated).unwrap(), Some(alice));
+        assert_eq!(trie.remove(b"bob").unwrap(), Some(bob));
+        trie.commit(Default::default(), 1).unwrap();
+        assert_eq!(trie.get(b"alice").unwrap(), Some(updated));
+        assert_eq!(trie.get(b"bob").unwrap(), None);
+    }
+
+    #[test]
+    fn test_typed_trie_cbor() {
+        check_round_trip(CborCodec);
+    }
+
+    #[test]
+    fn test_typed_trie_custom_codec() {
+        check_round_trip(FixedCodec);
+    }
+
+    #[test]
+    fn test_typed_trie_decode_error() {
+        let mut tree = new_tree();
+        tree.insert(b"alice", b"garbage").unwrap();
+        let trie = TypedTrie::with_codec(tree, FixedCodec);
+        assert!(trie.get(b"alice").is_err());
+    }
+}
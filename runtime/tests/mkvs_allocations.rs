@@ -0,0 +1,107 @@
+//! Allocation budgets for common MKVS tree operations.
+//!
+//! Allocations are counted by a global allocator, so these tests live in their own test
+//! binary instead of replacing the allocator for all library tests.
+// Invariant checks allocate diagnostics on every mutation.
+#![cfg(not(feature = "debug-invariants"))]
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+};
+
+use oasis_core_runtime::storage::mkvs::{sync::NoopReadSyncer, RootType, Tree};
+
+/// Number of keys in the tree used by the workloads.
+const TREE_KEYS: usize = 10_000;
+
+/// Maximum number of allocations for a single insert into a tree with a warm cache.
+const BUDGET_INSERT_ALLOCATIONS: usize = 50;
+
+/// A global allocator which counts allocations per thread, so that tests running
+/// concurrently on other threads do not affect the counts.
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+impl CountingAllocator {
+    fn count() {
+        // The counter may already be destroyed while the thread is exiting.
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+    }
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        Self::count();
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        Self::count();
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        Self::count();
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Return the number of allocations made by the current thread so far.
+fn allocation_count() -> usize {
+    ALLOCATIONS.with(Cell::get)
+}
+
+fn check_budget(name: &str, actual: usize, budget: usize) {
+    assert!(
+        actual <= budget,
+        "{}: actual {} exceeds budget {}",
+        name,
+        actual,
+        budget
+    );
+}
+
+fn generate_keys(count: usize, prefix: &str) -> Vec<Vec<u8>> {
+    (0..count)
+        .map(|i| format!("{}key {}", prefix, i).into_bytes())
+        .collect()
+}
+
+fn generate_tree(keys: &[Vec<u8>]) -> Tree {
+    let mut tree = Tree::builder()
+        .with_root_type(RootType::State)
+        .build(Box::new(NoopReadSyncer));
+    for key in keys {
+        tree.insert(key, b"value").expect("insert");
+    }
+    tree.commit(Default::default(), 0).expect("commit");
+    tree
+}
+
+#[test]
+fn test_budget_insert_allocations() {
+    let mut tree = generate_tree(&generate_keys(TREE_KEYS, ""));
+
+    let mut max_allocations = 0;
+    for key in generate_keys(100, "new/") {
+        let before = allocation_count();
+        tree.insert(&key, b"value").expect("insert");
+        max_allocations = max_allocations.max(allocation_count() - before);
+    }
+    check_budget(
+        "insert allocations",
+        max_allocations,
+        BUDGET_INSERT_ALLOCATIONS,
+    );
+}